@@ -0,0 +1,457 @@
+use std::{
+    collections::HashSet,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use image::ImageFormat;
+
+use futures_lite::StreamExt;
+use iroh::{NodeAddr, PublicKey};
+use iroh_blobs::{
+    BlobFormat, Hash, HashAndFormat, Tag,
+    get::db::DownloadProgress,
+    net_protocol::DownloadMode,
+    provider::AddProgress,
+    rpc::client::blobs::{BlobStatus, DownloadOptions, WrapOption},
+    store::{ExportFormat, ExportMode},
+    util::SetTagOption,
+};
+use iroh_docs::{AuthorId, store::Query};
+use tokio::sync::watch;
+
+use crate::{
+    client::{ChatClient, ChatError, StoredMessage, read_envelope},
+    iroh_client::{BlobsClient, Iroh},
+    message::{
+        Envelope, Message, MessageId, THUMBNAIL_METADATA, THUMBNAIL_SIZE_METADATA,
+        UPLOADER_METADATA,
+    },
+};
+
+/// Tag name prefix protecting unfinished downloads from garbage
+/// collection, so they can be resumed after a restart.
+const DOWNLOAD_TAG_PREFIX: &str = "chat-download/";
+
+/// Longest side of generated thumbnails, in pixels.
+const THUMBNAIL_SIZE: u32 = 160;
+
+/// Thumbnails larger than this many bytes are not fetched automatically.
+pub(crate) const MAX_THUMBNAIL_SIZE: u64 = 256 * 1024;
+
+/// Progress of an attachment upload or download.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferProgress {
+    /// bytes imported or downloaded so far
+    pub transferred: u64,
+    /// size of the attachment in bytes
+    pub total: u64,
+    /// the node we are downloading from, `None` for uploads
+    pub peer: Option<PublicKey>,
+}
+
+/// Cancels a running upload or download when triggered.
+#[derive(Debug, Clone)]
+pub struct CancelToken(Arc<watch::Sender<bool>>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    async fn cancelled(&self) {
+        let mut cancelled = self.0.subscribe();
+        let _ = cancelled.wait_for(|c| *c).await;
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChatClient {
+    /// Imports the file at `path` into the blob store and posts it as an
+    /// attachment. The file itself is not copied into the chat doc,
+    /// receivers fetch it with [`ChatClient::download_attachment`].
+    ///
+    /// Images get a small PNG thumbnail stored as a blob of its own, which
+    /// receivers fetch right away.
+    pub async fn send_file(
+        &mut self,
+        iroh: Arc<Iroh>,
+        author: AuthorId,
+        path: impl AsRef<Path>,
+        mut progress: impl FnMut(TransferProgress),
+        cancel: &CancelToken,
+    ) -> Result<MessageId, ChatError> {
+        self.check_writable()?;
+        // the blob store only imports absolute paths
+        let path = tokio::fs::canonicalize(path.as_ref())
+            .await
+            .map_err(|e| ChatError::Storage(e.into()))?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut import = iroh
+            .blobs
+            .add_from_path(path.clone(), false, SetTagOption::Auto, WrapOption::NoWrap)
+            .await?;
+        let mut total = 0;
+        let mut imported = None;
+        loop {
+            let event = tokio::select! {
+                event = import.next() => event,
+                _ = cancel.cancelled() => return Err(ChatError::Cancelled),
+            };
+            let Some(event) = event else {
+                break;
+            };
+            match event? {
+                AddProgress::Found { size, .. } => {
+                    total = size;
+                    progress(TransferProgress {
+                        transferred: 0,
+                        total,
+                        peer: None,
+                    });
+                }
+                AddProgress::Progress { offset, .. } => progress(TransferProgress {
+                    transferred: offset,
+                    total,
+                    peer: None,
+                }),
+                AddProgress::AllDone { hash, .. } => imported = Some(hash),
+                AddProgress::Abort(e) => return Err(ChatError::Storage(anyhow::anyhow!("{}", e))),
+                _ => {}
+            }
+        }
+        let hash = imported.ok_or_else(|| {
+            ChatError::Storage(anyhow::anyhow!(
+                "import of {} did not finish",
+                path.display()
+            ))
+        })?;
+        progress(TransferProgress {
+            transferred: total,
+            total,
+            peer: None,
+        });
+        let mime = mime_type(&path);
+        let msg = Message::new_attachment(author, hash, total, name, mime.to_string());
+        let mut envelope =
+            Envelope::new(author, msg).with_metadata(UPLOADER_METADATA, iroh.node_id().to_string());
+        if mime.starts_with("image/")
+            && let Some((thumbnail, size)) = import_thumbnail(&iroh, path).await
+        {
+            envelope = envelope
+                .with_metadata(THUMBNAIL_METADATA, thumbnail.to_string())
+                .with_metadata(THUMBNAIL_SIZE_METADATA, size.to_string());
+        }
+        self.send_envelope(envelope).await
+    }
+
+    /// Returns the thumbnail of the image attachment `envelope`, if it has
+    /// one. A thumbnail that was not fetched yet is fetched now, `None` if
+    /// no peer provides it within [`MAX_THUMBNAIL_SIZE`].
+    pub async fn thumbnail(
+        &self,
+        iroh: Arc<Iroh>,
+        envelope: &Envelope,
+    ) -> Result<Option<Vec<u8>>, ChatError> {
+        let Some(hash) = envelope.thumbnail() else {
+            return Ok(None);
+        };
+        if !self.has_blob(&iroh, hash).await {
+            let peers = self.peers(&iroh, envelope.uploader()).await;
+            if !fetch_thumbnail(&iroh.blobs, hash, peers).await {
+                return Ok(None);
+            }
+        }
+        let thumbnail = iroh.blobs.read_to_bytes(hash).await?;
+        Ok(Some(thumbnail.to_vec()))
+    }
+
+    /// Starts fetching the thumbnails of `page` that are not stored yet, so
+    /// that history loaded from the doc shows them like live messages do.
+    pub(crate) async fn prefetch_thumbnails(&self, iroh: &Iroh, page: &[StoredMessage]) {
+        let mut missing = Vec::new();
+        for message in page {
+            if let Some(hash) = message.envelope.thumbnail()
+                && !self.has_blob(iroh, hash).await
+            {
+                missing.push((hash, message.envelope.uploader()));
+            }
+        }
+        if missing.is_empty() {
+            return;
+        }
+        let peers = self.peers(iroh, None).await;
+        for (hash, uploader) in missing {
+            let blobs = iroh.blobs.clone();
+            let peers = uploader
+                .into_iter()
+                .chain(peers.iter().copied().filter(|peer| Some(*peer) != uploader))
+                .collect();
+            tokio::spawn(async move { fetch_thumbnail(&blobs, hash, peers).await });
+        }
+    }
+
+    /// Fetches the attachment `envelope` from the chat's peers, unless it
+    /// is already stored locally, and saves it to `dest`.
+    ///
+    /// The node that sent it is tried first, then the nodes the chat was
+    /// synced with and finally the current neighbors. Data received before a failure,
+    /// a cancellation or a restart is kept in the blob store, so calling
+    /// this again continues where the last attempt stopped.
+    pub async fn download_attachment(
+        &self,
+        iroh: Arc<Iroh>,
+        envelope: &Envelope,
+        dest: impl AsRef<Path>,
+        mut progress: impl FnMut(TransferProgress),
+        cancel: &CancelToken,
+    ) -> Result<(), ChatError> {
+        let Message::Attachment { hash, size, .. } = &envelope.message else {
+            return Err(ChatError::NotAnAttachment);
+        };
+        let (hash, size) = (*hash, *size);
+        if !self.has_blob(&iroh, hash).await {
+            let tag = Tag::from(format!("{}{}", DOWNLOAD_TAG_PREFIX, hash));
+            iroh.blobs
+                .tags()
+                .set(tag.clone(), HashAndFormat::raw(hash))
+                .await?;
+            let mut done = false;
+            for peer in self.peers(&iroh, envelope.uploader()).await {
+                if cancel.is_cancelled() {
+                    return Err(ChatError::Cancelled);
+                }
+                let mut on_progress = |transferred| {
+                    progress(TransferProgress {
+                        transferred,
+                        total: size,
+                        peer: Some(peer),
+                    })
+                };
+                if download_from(&iroh, hash, peer, &mut on_progress, cancel).await? {
+                    done = true;
+                    break;
+                }
+            }
+            if !done {
+                return Err(ChatError::Network(anyhow::anyhow!(
+                    "none of the chat's peers could provide {}",
+                    hash
+                )));
+            }
+            // the download tagged the complete blob itself
+            let _ = iroh.blobs.tags().delete(tag).await;
+        }
+        progress(TransferProgress {
+            transferred: size,
+            total: size,
+            peer: None,
+        });
+
+        // the export wants an absolute destination as well
+        let dest = std::path::absolute(dest.as_ref()).map_err(|e| ChatError::Storage(e.into()))?;
+        iroh.blobs
+            .export(hash, dest, ExportFormat::Blob, ExportMode::Copy)
+            .await?
+            .finish()
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the attachment messages of this chat whose download was
+    /// started but not finished, e.g. because the app was closed in between.
+    /// Pass them to [`ChatClient::download_attachment`] to resume.
+    pub async fn pending_downloads(&self, iroh: Arc<Iroh>) -> Result<Vec<Envelope>, ChatError> {
+        let mut tags = Box::pin(iroh.blobs.tags().list_prefix(DOWNLOAD_TAG_PREFIX).await?);
+        let mut hashes = HashSet::new();
+        while let Some(tag) = tags.next().await {
+            let tag = tag?;
+            if !self.has_blob(&iroh, tag.hash).await {
+                hashes.insert(tag.hash);
+            }
+        }
+        if hashes.is_empty() {
+            return Ok(Vec::new());
+        }
+        // the tags are shared by all chats, find the messages of this one
+        let creator = self.creator(&iroh).await?;
+        let mut entries = Box::pin(self.chat.get_many(Query::all().build()).await?);
+        let mut pending: Vec<Envelope> = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let key = String::from_utf8_lossy(entry.key()).into_owned();
+            let Some(envelope) = read_envelope(
+                &iroh.blobs,
+                &key,
+                entry.author(),
+                creator,
+                entry.content_hash(),
+            )
+            .await
+            else {
+                continue;
+            };
+            let Message::Attachment { hash, .. } = &envelope.message else {
+                continue;
+            };
+            // a rotated chat may hold a copy next to the original
+            if hashes.contains(hash) && !pending.iter().any(|known| known.id == envelope.id) {
+                pending.push(envelope);
+            }
+        }
+        Ok(pending)
+    }
+
+    async fn has_blob(&self, iroh: &Iroh, hash: Hash) -> bool {
+        matches!(
+            iroh.blobs.status(hash).await,
+            Ok(BlobStatus::Complete { .. })
+        )
+    }
+
+    /// The nodes to fetch an attachment from: `uploader`, then the nodes
+    /// this chat was synced with, then the current neighbors.
+    async fn peers(&self, iroh: &Iroh, uploader: Option<PublicKey>) -> Vec<PublicKey> {
+        let synced = match self.chat.get_sync_peers().await {
+            Ok(Some(synced)) => synced,
+            _ => Vec::new(),
+        };
+        let synced = synced
+            .iter()
+            .filter_map(|peer| PublicKey::from_bytes(peer).ok());
+        let neighbors = iroh.neighbors(self.id()).await;
+        let mut peers = Vec::new();
+        for peer in uploader.into_iter().chain(synced).chain(neighbors) {
+            if peer != iroh.node_id() && !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+}
+
+/// Downloads `hash` from `peer` only. Returns whether the blob is complete
+/// afterwards.
+async fn download_from(
+    iroh: &Iroh,
+    hash: Hash,
+    peer: PublicKey,
+    progress: &mut impl FnMut(u64),
+    cancel: &CancelToken,
+) -> Result<bool, ChatError> {
+    let options = DownloadOptions {
+        format: BlobFormat::Raw,
+        nodes: vec![NodeAddr::new(peer)],
+        tag: SetTagOption::Auto,
+        mode: DownloadMode::Direct,
+    };
+    let mut download = iroh
+        .blobs
+        .download_with_opts(hash, options)
+        .await
+        .map_err(ChatError::Network)?;
+    loop {
+        // dropping the progress stream stops the download, the data
+        // received so far stays in the store
+        let event = tokio::select! {
+            event = download.next() => event,
+            _ = cancel.cancelled() => return Err(ChatError::Cancelled),
+        };
+        match event {
+            Some(Ok(DownloadProgress::Progress { offset, .. })) => progress(offset),
+            Some(Ok(DownloadProgress::AllDone(_))) => return Ok(true),
+            Some(Ok(DownloadProgress::Abort(_))) | Some(Err(_)) | None => return Ok(false),
+            Some(Ok(_)) => {}
+        }
+    }
+}
+
+/// Fetches the thumbnail `hash` from the first of `peers` that has it.
+/// Peers announcing or sending more than [`MAX_THUMBNAIL_SIZE`] bytes are
+/// dropped, whatever size the sender put into the envelope. Returns whether
+/// the thumbnail is stored afterwards.
+pub(crate) async fn fetch_thumbnail(
+    blobs: &BlobsClient,
+    hash: Hash,
+    peers: Vec<PublicKey>,
+) -> bool {
+    for peer in peers {
+        let options = DownloadOptions {
+            format: BlobFormat::Raw,
+            nodes: vec![NodeAddr::new(peer)],
+            tag: SetTagOption::Auto,
+            mode: DownloadMode::Direct,
+        };
+        let Ok(mut download) = blobs.download_with_opts(hash, options).await else {
+            continue;
+        };
+        // dropping the progress stream stops the download
+        while let Some(Ok(event)) = download.next().await {
+            match event {
+                DownloadProgress::Found { size, .. } if size > MAX_THUMBNAIL_SIZE => break,
+                DownloadProgress::Progress { offset, .. } if offset > MAX_THUMBNAIL_SIZE => break,
+                DownloadProgress::AllDone(_) => return true,
+                DownloadProgress::Abort(_) => break,
+                _ => {}
+            }
+        }
+    }
+    false
+}
+
+/// Scales the image at `path` down to a thumbnail and adds it to the blob
+/// store, returning its hash and size. `None` if the file can't be decoded
+/// as an image.
+async fn import_thumbnail(iroh: &Iroh, path: PathBuf) -> Option<(Hash, u64)> {
+    let thumbnail = tokio::task::spawn_blocking(move || {
+        let image = image::open(path).ok()?;
+        let mut png = Vec::new();
+        image
+            .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .ok()?;
+        Some(png)
+    })
+    .await
+    .ok()??;
+    let outcome = iroh.blobs.add_bytes(thumbnail).await.ok()?;
+    Some((outcome.hash, outcome.size))
+}
+
+/// Guesses the MIME type of a file from its extension.
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "txt" | "md" => "text/plain",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
@@ -1,5 +1,5 @@
 use iroh_chat_cli::cli::start_cli;
-use iroh_chat_cli::iroh_client::Iroh;
+use iroh_chat_cli::iroh_client::IrohBuilder;
 use std::sync::Arc;
 
 #[cfg(target_os = "linux")]
@@ -14,7 +14,13 @@ const fn get_directory() -> &'static str{
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let i = Arc::new(Iroh::new(get_directory().into()).await?);
-    start_cli(i).await;
+    let mut builder = IrohBuilder::new().data_dir(get_directory());
+    // --lan: stay on the local network, without relays or n0 discovery
+    if std::env::args().any(|arg| arg == "--lan") {
+        builder = builder.local_network_only();
+    }
+    let i = Arc::new(builder.build().await?);
+    start_cli(i.clone()).await;
+    i.shutdown().await?;
     Ok(())
 }
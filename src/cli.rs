@@ -1,104 +1,702 @@
-use std::sync::Arc;
+use std::{io::Write, sync::Arc};
 
 use dialoguer::{theme::ColorfulTheme, Input};
 use futures_lite::StreamExt;
+use iroh_docs::{rpc::client::docs::ShareMode, AuthorId};
 use tokio::sync::mpsc;
 
-use crate::{client::ChatClient, message::Message, iroh_client::Iroh};
+use crate::{attachment::{CancelToken, TransferProgress}, client::{ChatClient, ChatError, ChatEvent, StoredMessage}, message::{Envelope, Message, MessageId}, invite::{qr_terminal, ticket_from_qr}, iroh_client::{ChatSummary, Iroh}, metadata::{ChatKind, META_PREFIX}};
 
+/// number of messages loaded per history page
+const HISTORY_PAGE: usize = 20;
 
-enum ChatType {
-    None,
-    Join,
-    Create,
-}
 pub async fn start_cli(i: Arc<Iroh>){
     println!("author: {}", i.author.fmt_short());
-    let mut ct = ChatType::None;
-    while let ChatType::None = ct {
-        let input: String = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Commmand:")
-            .interact_text()
-            .unwrap();
-        match input.as_str() {
-            "create" => {
-                ct = ChatType::Create;
-            }
-            "join" => {
-                ct = ChatType::Join;
-            }
+    // chats left with `switch`, kept open until the cli exits
+    let mut open: Vec<ChatClient> = Vec::new();
+    // the chats printed by `show`, referenced by their position
+    let mut listed = Vec::new();
+    // ctrl-c at the prompt ends the input
+    while let Ok(input) = Input::<String>::with_theme(&ColorfulTheme::default())
+        .with_prompt("Commmand:")
+        .interact_text()
+    {
+        let client = match input.as_str() {
+            "create" => ui_create(i.clone()).await,
+            "join" => ui_join(i.clone()).await,
             "show" => {
-                let docsa = i.docs.list().await.unwrap();
-                println!("{:?}", docsa.count().await)
+                match i.list_chats().await {
+                    Ok(chats) => {
+                        if chats.is_empty() {
+                            println!("no chats yet, `create` or `join` one");
+                        }
+                        for (number, chat) in chats.iter().enumerate() {
+                            print_chat(number, chat, &open);
+                        }
+                        listed = chats;
+                    }
+                    Err(e) => println!("could not list chats: {}", e),
+                }
+                continue;
             }
-            _ => {}
+            _ if input.starts_with("open ") => {
+                let Some(chat) = input["open ".len()..].trim().parse::<usize>().ok().and_then(|n| listed.get(n)) else {
+                    println!("usage: open <chat number from show>");
+                    continue;
+                };
+                match open.iter().position(|client| client.id() == chat.id) {
+                    Some(index) => Some(open.remove(index)),
+                    None => match i.open_chat(chat.id).await {
+                        Ok(client) => Some(client),
+                        Err(e) => {
+                            println!("could not open the chat: {}", e);
+                            None
+                        }
+                    },
+                }
+            }
+            _ => None,
+        };
+        let Some(client) = client else {
+            continue;
+        };
+        match ui_chat(client, i.clone()).await {
+            ChatExit::Switch(client) => open.push(client),
+            ChatExit::Closed => {}
+            ChatExit::Quit => break,
         }
     }
-    match ct {
-        ChatType::Join => ui_join(i).await,
-        ChatType::Create => ui_create(i).await,
-        _ => {}
+    for client in open {
+        let _ = client.close().await;
     }
 }
 
-async fn ui_create(node: Arc<Iroh>) {
-    let client = node.create_chat().await.unwrap();
-    ui_chat(client, node).await
+fn print_chat(number: usize, chat: &ChatSummary, open: &[ChatClient]) {
+    let title = chat.metadata.title.clone().unwrap_or_else(|| chat.id.fmt_short());
+    let marker = if open.iter().any(|client| client.id() == chat.id) { " (open)" } else { "" };
+    match chat.last_activity {
+        Some(timestamp) => println!("[{}] {}{}, last message {}", number, title, marker, age(timestamp)),
+        None => println!("[{}] {}{}, no messages", number, title, marker),
+    }
+    if let Some(topic) = &chat.metadata.topic {
+        println!("    {}", topic);
+    }
 }
 
-async fn ui_join(node: Arc<Iroh>) {
-    let join_ticket: String = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("ticket:")
-        .interact_text()
-        .unwrap();
-    // trim extra spaces
-    let join_ticket = join_ticket.trim();
-    let Ok(mut client) = node.join_chat(join_ticket.to_string()).await.unwrap() else {
-        panic!("")
+/// Formats how long ago `timestamp` (microseconds since the unix epoch) was.
+fn age(timestamp: u64) -> String {
+    let now = std::time::UNIX_EPOCH.elapsed().unwrap_or_default().as_micros() as u64;
+    let minutes = now.saturating_sub(timestamp) / 60_000_000;
+    match minutes {
+        0 => "just now".to_string(),
+        1..60 => format!("{} minutes ago", minutes),
+        60..1440 => format!("{} hours ago", minutes / 60),
+        _ => format!("{} days ago", minutes / 1440),
+    }
+}
+
+/// Prints title, topic and creator of the chat when entering it.
+async fn print_header(client: &mut ChatClient, node: Arc<Iroh>) {
+    let Ok(metadata) = client.metadata(node.clone()).await else {
+        return;
+    };
+    let title = metadata.title.unwrap_or_else(|| client.id().fmt_short());
+    match metadata.kind {
+        ChatKind::Announcement => println!("=== {} (announcements) ===", title),
+        ChatKind::Group => println!("=== {} ===", title),
+    }
+    if let Some(topic) = metadata.topic {
+        println!("{}", topic);
+    }
+    if let Some(creator) = metadata.creator {
+        let name = display_name(client, node, creator).await;
+        match metadata.created {
+            Some(created) => println!("created by {} {}", name, age(created)),
+            None => println!("created by {}", name),
+        }
+    }
+    if client.is_read_only() {
+        println!("you joined with a read-only ticket and can't post here");
+    }
+}
+
+async fn ui_create(node: Arc<Iroh>) -> Option<ChatClient> {
+    // empty answers leave the field unset
+    let ask = |prompt: &str| {
+        Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .allow_empty(true)
+            .interact_text()
+            .ok()
+            .filter(|answer| !answer.trim().is_empty())
+    };
+    let kind = match ask("announcement channel, only you can post? (y/N)").as_deref() {
+        Some("y") | Some("Y") => ChatKind::Announcement,
+        _ => ChatKind::Group,
+    };
+    let title = ask("title:");
+    let topic = ask("topic:");
+    match node.create_chat(kind, title, topic).await {
+        Ok(client) => {
+            let mode = match kind {
+                ChatKind::Group => ShareMode::Write,
+                ChatKind::Announcement => ShareMode::Read,
+            };
+            print_invite(&client, &node, mode).await;
+            Some(client)
+        }
+        Err(e) => {
+            println!("could not create the chat: {}", e);
+            None
+        }
+    }
+}
+
+async fn ui_join(node: Arc<Iroh>) -> Option<ChatClient> {
+    loop {
+        let Ok(join_ticket) = Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt("ticket or QR code image:")
+            .interact_text() else {
+            return None;
+        };
+        // trim extra spaces
+        let join_ticket = join_ticket.trim();
+        // a path is a picture of the QR code
+        let join_ticket = match tokio::fs::read(join_ticket).await {
+            Ok(image) => match ticket_from_qr(&image) {
+                Ok(ticket) => ticket,
+                Err(e) => {
+                    println!("could not read the QR code: {}, try again", e);
+                    continue;
+                }
+            },
+            Err(_) => join_ticket.to_string(),
+        };
+        match node.join_chat(join_ticket).await {
+            Ok(client) => return Some(client),
+            Err(e) => println!("could not join: {}, try again", e),
+        }
+    }
+}
+
+/// Prints a ticket for the chat as a QR code and as text.
+async fn print_invite(client: &ChatClient, node: &Iroh, mode: ShareMode) {
+    let ticket = match node.ticket(client, mode).await {
+        Ok(ticket) => ticket.to_string(),
+        Err(ChatError::PermissionDenied) => {
+            println!("announcement channels only have read-only tickets");
+            return;
+        }
+        Err(e) => {
+            println!("could not create the ticket: {}", e);
+            return;
+        }
     };
-    ui_chat(client, node).await
+    if let Ok(qr) = qr_terminal(&ticket) {
+        println!("{}", qr);
+    }
+    println!("ticket: {}", ticket);
+}
+
+/// How the user got out of [`ui_chat`].
+enum ChatExit {
+    /// switched to the command menu, the chat stays open
+    Switch(ChatClient),
+    /// left or deleted the chat
+    Closed,
+    /// quit with ctrl-c
+    Quit,
 }
 
-async fn ui_chat(mut client: ChatClient, node: Arc<Iroh>) {
+/// Runs the chat until the user switches away, leaves or deletes the chat,
+/// or quits with ctrl-c.
+async fn ui_chat(client: ChatClient, node: Arc<Iroh>) -> ChatExit {
+    let mut client = follow_moves(client, node.clone()).await;
+    let Ok(mut events) = client.events(node.clone()).await else {
+        println!("could not subscribe to the chat");
+        return ChatExit::Switch(client);
+    };
+    // ids of the messages printed so far, referenced by their position
+    let mut shown = Vec::new();
+    // root of the thread we are in, `None` for the main timeline
+    let mut thread: Option<MessageId> = None;
+    let mut oldest = print_intro(&mut client, node.clone(), &mut shown).await;
     let (tx1, mut rx1) = mpsc::channel(32);
     tokio::spawn(async move {
-        loop {
-            let line: String = Input::with_theme(&ColorfulTheme::default())
-                .with_prompt("Chat:")
-                .interact_text()
-                .unwrap();
-            let _ = tx1.send(line).await;
+        while let Ok(line) = Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt("Chat:")
+            .interact_text()
+        {
+            // the prompt belongs to the command menu after these
+            let to_menu = matches!(line.as_str(), "switch" | "leave" | "delete chat");
+            if tx1.send(line).await.is_err() || to_menu {
+                break;
+            }
         }
     });
 
+    // `switch`, `leave` or `delete chat`, `None` on ctrl-c
+    let mut exit_command = None;
+    // the chat to continue in after `rotate` or a move marker
+    let mut moved = None;
     loop {
         tokio::select! {
             line = rx1.recv() => {
-                let line = line.unwrap();
-                if line.clone().starts_with("set name "){
+                // the input task ends on ctrl-c at the prompt
+                let Some(line) = line else {
+                    break;
+                };
+                if matches!(line.as_str(), "switch" | "leave" | "delete chat") {
+                    exit_command = Some(line);
+                    break;
+                }else if line == "rotate" {
+                    match node.rotate_chat(&mut client).await {
+                        Ok(next) => {
+                            println!("chat moved, hand new tickets to the members you want to keep:");
+                            print_invite(&next, &node, ShareMode::Write).await;
+                            println!("read-only:");
+                            print_invite(&next, &node, ShareMode::Read).await;
+                            moved = Some(next);
+                        }
+                        Err(ChatError::PermissionDenied) => println!("only the creator can rotate the chat"),
+                        Err(e) => println!("could not rotate the chat: {}", e),
+                    }
+                }else if let Some(title) = line.strip_prefix("set title "){
+                    let title = title.trim().to_string();
+                    if let Err(e) = client.set_title(node.author, title).await {
+                        println!("could not set the title: {}", e);
+                    }
+                }else if line == "ticket" || line == "ticket write" {
+                    let mode = if line == "ticket" { ShareMode::Read } else { ShareMode::Write };
+                    print_invite(&client, &node, mode).await;
+                }else if let Some(topic) = line.strip_prefix("set topic "){
+                    let topic = topic.trim().to_string();
+                    if let Err(e) = client.set_topic(node.author, topic).await {
+                        println!("could not set the topic: {}", e);
+                    }
+                }else if let Some(path) = line.strip_prefix("set avatar "){
+                    let path = path.trim();
+                    let result = match tokio::fs::read(path).await {
+                        Ok(image) => client.set_avatar(node.clone(), node.author, image).await,
+                        Err(e) => Err(ChatError::Storage(e.into())),
+                    };
+                    if let Err(e) = result {
+                        println!("could not set the avatar: {}", e);
+                    }
+                }else if line.clone().starts_with("set name "){
                     let name = line.split(" ").last().unwrap();
                     let _ = client.set_author_name(node.clone().author, name.to_string()).await;
+                }else if line == "threads" {
+                    let Ok(threads) = client.threads().await else {
+                        continue;
+                    };
+                    if threads.is_empty() {
+                        println!("no threads yet, start one with: thread <message number>");
+                    }
+                    for summary in threads {
+                        match shown.iter().position(|id| *id == summary.root) {
+                            Some(number) => println!("thread on [{}]: {} messages", number, summary.replies),
+                            None => println!("thread on an earlier message: {} messages", summary.replies),
+                        }
+                    }
+                }else if let Some(args) = line.strip_prefix("thread "){
+                    let Some((root, _)) = parse_target(&shown, args) else {
+                        println!("usage: thread <message number>");
+                        continue;
+                    };
+                    thread = Some(root);
+                    println!("--- thread, type `back` to return to the chat ---");
+                    if let Ok(messages) = client.thread_messages(node.clone(), root, HISTORY_PAGE).await {
+                        for stored in messages {
+                            print_stored(&mut client, node.clone(), &mut shown, stored).await;
+                        }
+                    }
+                }else if line == "back" {
+                    if thread.take().is_some() {
+                        println!("--- back in the chat ---");
+                    }
+                }else if line == "more" {
+                    let Some(cursor) = oldest.clone() else {
+                        println!("no earlier messages");
+                        continue;
+                    };
+                    let Ok(history) = client.history_before(node.clone(), &cursor, HISTORY_PAGE).await else {
+                        continue;
+                    };
+                    if let Some(first) = history.first() {
+                        oldest = Some(first.key.clone());
+                    }
+                    println!("--- earlier messages ---");
+                    for stored in history {
+                        print_stored(&mut client, node.clone(), &mut shown, stored).await;
+                    }
+                    println!("---");
+                }else if let Some(args) = line.strip_prefix("reply "){
+                    let Some((reply_to, content)) = parse_target(&shown, args) else {
+                        println!("usage: reply <message number> <text>");
+                        continue;
+                    };
+                    match thread {
+                        Some(root) => {
+                            let reply = Message::new_reply(node.author, content.to_string(), reply_to);
+                            let _ = client.send_thread_message(node.author, root, reply).await;
+                        }
+                        None => {
+                            let _ = client.reply(node.author, reply_to, content.to_string()).await;
+                        }
+                    }
+                }else if let Some(args) = line.strip_prefix("edit "){
+                    let Some((target, content)) = parse_target(&shown, args) else {
+                        println!("usage: edit <message number> <text>");
+                        continue;
+                    };
+                    if let Err(ChatError::PermissionDenied) = client.edit_message(node.clone(), node.author, target, content.to_string()).await {
+                        println!("you can only edit your own messages");
+                    }
+                }else if let Some(args) = line.strip_prefix("react "){
+                    let Some((target, emoji)) = parse_target(&shown, args) else {
+                        println!("usage: react <message number> <emoji>");
+                        continue;
+                    };
+                    if emoji.is_empty() {
+                        println!("usage: react <message number> <emoji>");
+                        continue;
+                    }
+                    let _ = client.toggle_reaction(node.clone(), node.author, target, emoji.to_string()).await;
+                }else if let Some(path) = line.strip_prefix("send "){
+                    let path = path.trim();
+                    let msg = client.send_file(node.clone(), node.author, path, print_progress, &CancelToken::new()).await;
+                    println!();
+                    if msg.is_err() {
+                        println!("could not send {}", path);
+                    }
+                }else if let Some(args) = line.strip_prefix("download "){
+                    let Some((target, dest)) = parse_target(&shown, args) else {
+                        println!("usage: download <message number> <destination>");
+                        continue;
+                    };
+                    let Ok(Some(envelope)) = client.get_message(node.clone(), target).await else {
+                        continue;
+                    };
+                    let dest = match (dest, &envelope.message) {
+                        ("", Message::Attachment { name, .. }) => name.clone(),
+                        _ => dest.to_string(),
+                    };
+                    match client.download_attachment(node.clone(), &envelope, &dest, print_progress, &CancelToken::new()).await {
+                        Ok(()) => println!("\nsaved to {}", dest),
+                        Err(ChatError::NotAnAttachment) => println!("that message is not an attachment"),
+                        Err(_) => println!("\ndownload failed"),
+                    }
+                }else if let Some(args) = line.strip_prefix("delete "){
+                    let Some((target, _)) = parse_target(&shown, args) else {
+                        println!("usage: delete <message number>");
+                        continue;
+                    };
+                    if let Err(ChatError::PermissionDenied) = client.delete_message(node.clone(), node.author, target).await {
+                        println!("you can only delete your own messages");
+                    }
                 }else{
-                    let _ = client.send_message(node.clone().author, Message::new_text(node.author, line.clone())).await;
+                    let msg = Message::new_text(node.author, line.clone());
+                    let sent = match thread {
+                        Some(root) => client.send_thread_message(node.author, root, msg).await,
+                        None => client.send_message(node.clone().author, msg).await,
+                    };
+                    if let Err(ChatError::ReadOnly) = sent {
+                        println!("this chat is read-only, your message was not sent");
+                    }
                 }
             }
-            Ok(msg) = client.message_receiver_loop(node.clone()) => {
-                match msg{
-                Message::TextMessage { author, content } => {
-                    let name = client.get_author_name(author, node.clone()).await;
-                    match name{
-                        Ok(a) => println!("{}:{}", a, content.to_string()),
-                        Err(_) => println!("{}:{}", author.fmt_short(), content.to_string()),
-                    }
-                                    }
-                Message::ChatTicket{
-                    author, ..
-                } => {
-                    println!("\n{} joined!!",author.fmt_short())
+            Some(event) = events.next() => {
+                match event {
+                    ChatEvent::MessageReceived { envelope, .. }
+                    | ChatEvent::MessageSent { envelope, .. } => {
+                        // edits and reactions are shown wherever their target is
+                        let in_view = matches!(envelope.message, Message::Edit { .. } | Message::Reaction { .. })
+                            || envelope.thread() == thread;
+                        if let Message::Moved { .. } = envelope.message {
+                            match node.follow_move(&client).await {
+                                Ok(Some(next)) => moved = Some(next),
+                                _ => print_move_notice(&client, node.clone()).await,
+                            }
+                        } else if in_view {
+                            print_message(&mut client, node.clone(), &mut shown, envelope, None).await
+                        } else if let Some(root) = envelope.thread() {
+                            match shown.iter().position(|id| *id == root) {
+                                Some(number) => println!("(new message in the thread on [{}])", number),
+                                None => println!("(new message in a thread)"),
+                            }
+                        } else {
+                            println!("(new message in the chat)");
+                        }
+                    }
+                    ChatEvent::EntryChanged { key, .. } if key.starts_with(META_PREFIX) => {
+                        println!();
+                        print_header(&mut client, node.clone()).await;
+                    }
+                    ChatEvent::NeighborUp(peer) => println!("\n{} connected", peer.fmt_short()),
+                    ChatEvent::NeighborDown(peer) => println!("\n{} disconnected", peer.fmt_short()),
+                    _ => {}
                 }
-                _ => {}
             }
+            _ = tokio::signal::ctrl_c() => break,
+            else => break,
+        }
+        if let Some(next) = moved.take() {
+            drop(events);
+            let previous = std::mem::replace(&mut client, follow_moves(next, node.clone()).await);
+            let _ = previous.close().await;
+            println!("--- continuing in the new chat ---");
+            let Ok(next_events) = client.events(node.clone()).await else {
+                println!("could not subscribe to the chat");
+                return ChatExit::Switch(client);
+            };
+            events = next_events;
+            shown.clear();
+            thread = None;
+            oldest = print_intro(&mut client, node.clone(), &mut shown).await;
+        }
+    }
+    drop(events);
+    match exit_command.as_deref() {
+        Some("switch") => return ChatExit::Switch(client),
+        Some("leave") => match node.leave_chat(client).await {
+            Ok(()) => println!("you left the chat, `open` it again to rejoin"),
+            Err(e) => println!("could not leave the chat: {}", e),
+        },
+        Some(_) => match node.delete_chat(client).await {
+            Ok(()) => println!("chat deleted"),
+            Err(e) => println!("could not delete the chat: {}", e),
+        },
+        None => {
+            if let Err(e) = client.close().await {
+                println!("could not close the chat: {}", e);
             }
+            return ChatExit::Quit;
+        }
+    }
+    ChatExit::Closed
+}
+
+/// Prints the header, the latest messages and unfinished downloads when
+/// entering a chat. Returns the cursor for loading older messages.
+async fn print_intro(client: &mut ChatClient, node: Arc<Iroh>, shown: &mut Vec<MessageId>) -> Option<String> {
+    let mut oldest = None;
+    print_header(client, node.clone()).await;
+    if let Ok(history) = client.history_latest(node.clone(), HISTORY_PAGE).await {
+        oldest = history.first().map(|m| m.key.clone());
+        for stored in history {
+            print_stored(client, node.clone(), shown, stored).await;
+        }
+    }
+    if let Ok(pending) = client.pending_downloads(node.clone()).await {
+        for envelope in pending {
+            if let Message::Attachment { name, .. } = &envelope.message {
+                println!("[{}] unfinished download of {}, `download {}` to resume", shown.len(), name, shown.len());
+                shown.push(envelope.id);
+            }
+        }
+    }
+    oldest
+}
+
+/// Follows the move markers of `client` to the chat it continues in, as far
+/// as this node holds tickets for the new chats.
+async fn follow_moves(mut client: ChatClient, node: Arc<Iroh>) -> ChatClient {
+    while let Ok(Some(next)) = node.follow_move(&client).await {
+        println!("this chat moved, continuing in the new chat");
+        let _ = client.close().await;
+        client = next;
+    }
+    print_move_notice(&client, node).await;
+    client
+}
+
+async fn print_move_notice(client: &ChatClient, node: Arc<Iroh>) {
+    if let Ok(Some(_)) = client.moved_to(node).await {
+        println!("this chat moved to a new chat, ask its creator for a ticket");
+    }
+}
+
+fn print_progress(progress: TransferProgress) {
+    match progress.peer {
+        Some(peer) => print!(
+            "\r{}/{} bytes from {}",
+            progress.transferred,
+            progress.total,
+            peer.fmt_short()
+        ),
+        None => print!("\r{}/{} bytes", progress.transferred, progress.total),
+    }
+    let _ = std::io::stdout().flush();
+}
+
+/// Splits `<message number> <rest>` and resolves the number against the
+/// messages printed so far.
+fn parse_target<'a>(shown: &[MessageId], args: &'a str) -> Option<(MessageId, &'a str)> {
+    let (number, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let id = shown.get(number.parse::<usize>().ok()?)?;
+    Some((*id, rest.trim()))
+}
+
+async fn display_name(client: &mut ChatClient, node: Arc<Iroh>, author: AuthorId) -> String {
+    match client.get_author_name(author, node).await {
+        Ok(Some(name)) => name,
+        _ => author.fmt_short(),
+    }
+}
+
+/// Shortens `content` to a single line for quoting.
+fn snippet(content: &str) -> String {
+    let line = content.lines().next().unwrap_or_default();
+    if line.chars().count() > 40 {
+        format!("{}...", line.chars().take(40).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+/// Formats the reactions to `target` on one line, e.g. `👍 2  🎉 1`.
+async fn format_reactions(
+    client: &mut ChatClient,
+    node: Arc<Iroh>,
+    target: MessageId,
+) -> Option<String> {
+    let reactions = client.reactions(node, target).await.ok()?;
+    if reactions.is_empty() {
+        return None;
+    }
+    let counts: Vec<String> = reactions
+        .iter()
+        .map(|(emoji, authors)| format!("{} {}", emoji, authors.len()))
+        .collect();
+    Some(counts.join("  "))
+}
+
+async fn print_reactions(client: &mut ChatClient, node: Arc<Iroh>, target: MessageId) {
+    if let Some(reactions) = format_reactions(client, node, target).await {
+        println!("    {}", reactions);
+    }
+}
+
+/// Prints a message read from the history in its latest version.
+async fn print_stored(
+    client: &mut ChatClient,
+    node: Arc<Iroh>,
+    shown: &mut Vec<MessageId>,
+    stored: StoredMessage,
+) {
+    let edited = match stored.edit.map(|edit| edit.message) {
+        Some(Message::Edit { content, .. }) => Some(content),
+        _ => None,
+    };
+    print_message(client, node, shown, stored.envelope, edited).await
+}
+
+/// Prints `envelope`, with `edited` replacing its text if the message was
+/// edited since.
+async fn print_message(
+    client: &mut ChatClient,
+    node: Arc<Iroh>,
+    shown: &mut Vec<MessageId>,
+    envelope: Envelope,
+    edited: Option<String>,
+) {
+    let number = shown.len();
+    let marker = if edited.is_some() { " (edited)" } else { "" };
+    let has_thumbnail = envelope.thumbnail().is_some();
+    match envelope.message {
+        Message::TextMessage { author, content } => {
+            let name = display_name(client, node.clone(), author).await;
+            println!("[{}] {}:{}{}", number, name, edited.unwrap_or(content), marker);
+            print_reactions(client, node, envelope.id).await;
+        }
+        Message::Reply {
+            author,
+            content,
+            reply_to,
+        } => {
+            match client.get_message(node.clone(), reply_to).await {
+                Ok(Some(quoted)) => {
+                    let quoted_name = display_name(client, node.clone(), quoted.author()).await;
+                    let quoted_text = match quoted.message {
+                        Message::TextMessage { content, .. } | Message::Reply { content, .. } => {
+                            snippet(&content)
+                        }
+                        Message::Delete { .. } => "<message deleted>".to_string(),
+                        _ => "...".to_string(),
+                    };
+                    println!("    > {}: {}", quoted_name, quoted_text);
+                }
+                _ => println!("    > <message not available>"),
+            }
+            let name = display_name(client, node.clone(), author).await;
+            println!("[{}] {}:{}{}", number, name, edited.unwrap_or(content), marker);
+            print_reactions(client, node, envelope.id).await;
+        }
+        Message::Attachment {
+            author,
+            name: file_name,
+            size,
+            ..
+        } => {
+            let name = display_name(client, node.clone(), author).await;
+            let preview = if has_thumbnail { ", preview" } else { "" };
+            println!("[{}] {}: [file] {} ({} bytes{})", number, name, file_name, size, preview);
+            print_reactions(client, node, envelope.id).await;
+        }
+        Message::Edit {
+            author,
+            target,
+            content,
+        } => {
+            let name = display_name(client, node, author).await;
+            match shown.iter().position(|id| *id == target) {
+                Some(edited_number) => {
+                    println!("[{}] {}:{} (edited)", edited_number, name, content)
+                }
+                None => println!("{}:{} (edited)", name, content),
+            }
+            return;
+        }
+        Message::Delete { target, .. } => {
+            match shown.iter().position(|id| *id == target) {
+                Some(deleted_number) => println!("[{}] <message deleted>", deleted_number),
+                None => {
+                    println!("[{}] <message deleted>", number);
+                    shown.push(target);
+                }
+            }
+            return;
+        }
+        Message::Reaction { target, .. } => {
+            if let Some(reacted_number) = shown.iter().position(|id| *id == target) {
+                let reactions = format_reactions(client, node, target).await;
+                println!(
+                    "[{}] reactions: {}",
+                    reacted_number,
+                    reactions.unwrap_or_else(|| "none".to_string())
+                );
+            }
+            return;
+        }
+        Message::ChatTicket { author, .. } => {
+            println!("\n{} joined!!", author.fmt_short());
+            return;
+        }
+        Message::Leave { author } => {
+            let name = display_name(client, node, author).await;
+            println!("{} left the chat", name);
+            return;
+        }
+        Message::Unsupported { kind } => {
+            println!(
+                "[{}] {}: <unsupported message of kind {}, update to view it>",
+                number,
+                envelope.id.author.fmt_short(),
+                kind
+            )
         }
+        _ => return,
     }
+    shown.push(envelope.id);
 }
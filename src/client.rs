@@ -1,47 +1,431 @@
-use std::{pin::Pin, sync::Arc, thread, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    pin::Pin,
+    sync::Arc,
+};
 
 use quic_rpc::transport::flume::FlumeConnector;
 
 use futures_lite::{Stream, StreamExt};
+use iroh::{NodeAddr, PublicKey};
+use iroh_blobs::Hash;
 use iroh_docs::{
-    AuthorId,
+    AuthorId, CapabilityKind, NamespaceId,
     engine::LiveEvent,
     rpc::{
-        client::docs::Doc,
+        client::docs::{Doc, Entry},
         proto::{Request, Response},
     },
-    store::{Query, QueryBuilder},
+    store::{Query, SortBy, SortDirection},
 };
+use tokio::sync::mpsc;
 
-use crate::{iroh_client::Iroh, message::Message};
+use crate::{
+    attachment::{MAX_THUMBNAIL_SIZE, fetch_thumbnail},
+    iroh_client::{BlobsClient, Iroh},
+    message::{Envelope, MIGRATED_METADATA, Message, MessageId},
+    metadata::{AVATAR_KEY, chat_creator},
+};
 
 pub(crate) type ChatC = Doc<FlumeConnector<Response, Request>>;
 pub(crate) type SubC =
     Pin<Box<dyn Stream<Item = Result<LiveEvent, anyhow::Error>> + Send + 'static>>;
+pub type ChatEvents = Pin<Box<dyn Stream<Item = ChatEvent> + Send + 'static>>;
 
-pub(crate) struct ChatClient {
-    pub chat: ChatC,
-    pub sub: SubC,
+pub struct ChatClient {
+    pub(crate) chat: ChatC,
+    pub(crate) sub: Option<SubC>,
+    /// what the local replica of the chat document allows
+    pub(crate) capability: CapabilityKind,
+}
+
+/// Everything the chat document subscription reports, with message entries
+/// already decoded.
+#[derive(Debug)]
+pub enum ChatEvent {
+    /// A message inserted by another node. Messages of a kind this build
+    /// does not know arrive as [`Message::Unsupported`].
+    MessageReceived {
+        from: PublicKey,
+        key: String,
+        envelope: Envelope,
+    },
+    /// A message inserted by this node.
+    MessageSent {
+        key: String,
+        envelope: Envelope,
+    },
+    /// An entry that is not a message was inserted, e.g. the chat's title
+    /// or a member's name. `from` is `None` for entries written on this
+    /// node.
+    EntryChanged {
+        from: Option<PublicKey>,
+        key: String,
+        author: AuthorId,
+    },
+    /// A sync run with `peer` finished.
+    SyncFinished {
+        peer: PublicKey,
+        result: Result<(), String>,
+    },
+    NeighborUp(PublicKey),
+    NeighborDown(PublicKey),
+    /// The content for `hash` was downloaded and is available locally.
+    ContentReady(Hash),
+    /// All content downloads queued so far have finished or failed.
+    PendingContentReady,
+    /// The thumbnail of the image attachment `id` was fetched, read it with
+    /// [`ChatClient::thumbnail`].
+    ThumbnailReady {
+        id: MessageId,
+        hash: Hash,
+    },
 }
 
 impl ChatClient {
-    pub async fn send_message(&mut self, author: AuthorId, msg: Message) -> Result<(), ChatError> {
-        match self
-            .chat
-            .set_bytes(
-                author,
-                std::time::UNIX_EPOCH
-                    .elapsed()
-                    .unwrap()
-                    .as_micros()
-                    .to_string(),
-                bincode::serialize(&msg).unwrap(),
+    pub async fn send_message(
+        &mut self,
+        author: AuthorId,
+        msg: Message,
+    ) -> Result<MessageId, ChatError> {
+        self.send_envelope(Envelope::new(author, msg)).await
+    }
+
+    /// Writes `envelope` under the key derived from it.
+    pub async fn send_envelope(&mut self, envelope: Envelope) -> Result<MessageId, ChatError> {
+        self.check_writable()?;
+        let bytes = envelope.encode()?;
+        self.chat
+            .set_bytes(envelope.author(), envelope.key(), bytes)
+            .await?;
+        Ok(envelope.id)
+    }
+
+    /// Sends `content` as a reply to the message `reply_to`.
+    pub async fn reply(
+        &mut self,
+        author: AuthorId,
+        reply_to: MessageId,
+        content: String,
+    ) -> Result<MessageId, ChatError> {
+        self.send_message(author, Message::new_reply(author, content, reply_to))
+            .await
+    }
+
+    /// Posts `msg` in the thread started by the message `root`.
+    pub async fn send_thread_message(
+        &mut self,
+        author: AuthorId,
+        root: MessageId,
+        msg: Message,
+    ) -> Result<MessageId, ChatError> {
+        self.send_envelope(Envelope::new(author, msg).in_thread(root))
+            .await
+    }
+
+    /// Publishes `content` as the new version of `target`. Only the author
+    /// of `target` may edit it.
+    pub async fn edit_message(
+        &mut self,
+        iroh: Arc<Iroh>,
+        author: AuthorId,
+        target: MessageId,
+        content: String,
+    ) -> Result<MessageId, ChatError> {
+        if target.author != author {
+            return Err(ChatError::PermissionDenied);
+        }
+        let envelope = Envelope::new(author, Message::new_edit(author, target, content));
+        let envelope = self.stamp_thread(&iroh, envelope, target).await?;
+        self.send_envelope(envelope).await
+    }
+
+    /// Deletes `target` by overwriting it with a tombstone and dropping its
+    /// edits. The content of the message and its edits, and the file and
+    /// thumbnail of an attachment, are removed from the local blob store;
+    /// peers drop it once the tombstone reaches them.
+    /// Only the author of `target` may delete it.
+    pub async fn delete_message(
+        &mut self,
+        iroh: Arc<Iroh>,
+        author: AuthorId,
+        target: MessageId,
+    ) -> Result<(), ChatError> {
+        if target.author != author {
+            return Err(ChatError::PermissionDenied);
+        }
+        let creator = self.creator(&iroh).await?;
+        let mut hashes = Vec::new();
+        for signer in signers(target, creator) {
+            let query = Query::author(signer)
+                .key_prefix(target.edits_prefix())
+                .build();
+            let mut entries = Box::pin(self.chat.get_many(query).await?);
+            while let Some(entry) = entries.next().await {
+                hashes.push(entry?.content_hash());
+            }
+        }
+        let mut tombstone = Envelope::new(author, Message::new_delete(author, target));
+        if let Some(entry) = self.locate(target, creator).await? {
+            // the tombstone has to land on the same key as the message
+            let key = String::from_utf8_lossy(entry.key()).into_owned();
+            if let Some((root, _)) = MessageId::from_thread_key(&key) {
+                tombstone = tombstone.in_thread(root);
+            }
+            if let Some(envelope) = read_envelope(
+                &iroh.blobs,
+                &key,
+                entry.author(),
+                creator,
+                entry.content_hash(),
             )
             .await
-        {
-            Ok(_) => Ok(()),
-            Err(_) => Err(ChatError::SendError),
+            {
+                hashes.extend(envelope.thumbnail());
+                if let Message::Attachment { hash, .. } = envelope.message {
+                    hashes.push(hash);
+                }
+            }
+            hashes.push(entry.content_hash());
+        }
+
+        self.send_envelope(tombstone).await?;
+        self.chat.del(author, target.edits_prefix()).await?;
+        // deleting a blob ignores tags, so keep whatever another message or
+        // chat still refers to, e.g. the same file sent twice or a copy in
+        // a rotated chat
+        let in_use = iroh.blobs_in_use().await?;
+        for hash in hashes {
+            if !in_use.contains(&hash) {
+                let _ = iroh.blobs.delete_blob(hash).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `emoji` to `target` from `author`, or removes it if `author`
+    /// already reacted with it.
+    pub async fn toggle_reaction(
+        &mut self,
+        iroh: Arc<Iroh>,
+        author: AuthorId,
+        target: MessageId,
+        emoji: String,
+    ) -> Result<MessageId, ChatError> {
+        let key = format!("{}{}", target.reactions_prefix(), emoji);
+        let query = Query::author(author).key_exact(&key).build();
+        let active = match self.chat.get_one(query).await? {
+            Some(entry) => matches!(
+                read_envelope(&iroh.blobs, &key, author, None, entry.content_hash()).await,
+                Some(Envelope {
+                    message: Message::Reaction { active: true, .. },
+                    ..
+                })
+            ),
+            None => false,
+        };
+        let envelope = Envelope::new(
+            author,
+            Message::new_reaction(author, target, emoji, !active),
+        );
+        let envelope = self.stamp_thread(&iroh, envelope, target).await?;
+        self.send_envelope(envelope).await
+    }
+
+    /// Marks `envelope` as posted in the thread of `target`, if `target`
+    /// was posted in one. Edits and reactions are keyed by their target,
+    /// this lets thread views pick them up.
+    async fn stamp_thread(
+        &self,
+        iroh: &Iroh,
+        envelope: Envelope,
+        target: MessageId,
+    ) -> Result<Envelope, ChatError> {
+        let creator = self.creator(iroh).await?;
+        let Some(entry) = self.locate(target, creator).await? else {
+            return Ok(envelope);
+        };
+        let key = String::from_utf8_lossy(entry.key()).into_owned();
+        Ok(match MessageId::from_thread_key(&key) {
+            Some((root, _)) => envelope.in_thread(root),
+            None => envelope,
+        })
+    }
+
+    /// Returns the authors currently reacting to `target`, by emoji.
+    pub async fn reactions(
+        &self,
+        iroh: Arc<Iroh>,
+        target: MessageId,
+    ) -> Result<BTreeMap<String, Vec<AuthorId>>, ChatError> {
+        // every author has their own entry per emoji, so this yields the
+        // latest state of each author's reaction
+        let query = Query::key_prefix(target.reactions_prefix()).build();
+        let mut entries = Box::pin(self.chat.get_many(query).await?);
+        let mut reactions: BTreeMap<String, Vec<AuthorId>> = BTreeMap::new();
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let key = String::from_utf8_lossy(entry.key()).into_owned();
+            if let Some(Envelope {
+                message:
+                    Message::Reaction {
+                        emoji,
+                        target: reacted,
+                        active: true,
+                        ..
+                    },
+                id,
+                ..
+            }) = read_envelope(
+                &iroh.blobs,
+                &key,
+                entry.author(),
+                None,
+                entry.content_hash(),
+            )
+            .await
+                && reacted == target
+            {
+                reactions.entry(emoji).or_default().push(id.author);
+            }
+        }
+        Ok(reactions)
+    }
+
+    /// Returns the most recent edit of `id`, if it was edited.
+    pub async fn latest_edit(
+        &self,
+        iroh: Arc<Iroh>,
+        id: MessageId,
+    ) -> Result<Option<Envelope>, ChatError> {
+        let creator = self.creator(&iroh).await?;
+        self.latest_version(&iroh, creator, id).await
+    }
+
+    async fn latest_version(
+        &self,
+        iroh: &Iroh,
+        creator: Option<AuthorId>,
+        id: MessageId,
+    ) -> Result<Option<Envelope>, ChatError> {
+        let mut versions = self.versions(iroh, creator, id).await?;
+        if versions.len() < 2 {
+            return Ok(None);
+        }
+        Ok(versions.pop())
+    }
+
+    /// Returns every version of `id`, the original first and then its edits
+    /// oldest first. Empty if the message is not known locally.
+    pub async fn message_versions(
+        &self,
+        iroh: Arc<Iroh>,
+        id: MessageId,
+    ) -> Result<Vec<Envelope>, ChatError> {
+        let creator = self.creator(&iroh).await?;
+        self.versions(&iroh, creator, id).await
+    }
+
+    async fn versions(
+        &self,
+        iroh: &Iroh,
+        creator: Option<AuthorId>,
+        id: MessageId,
+    ) -> Result<Vec<Envelope>, ChatError> {
+        let Some(original) = self.find_message(iroh, creator, id).await? else {
+            return Ok(Vec::new());
+        };
+        if let Message::Delete { .. } = original.message {
+            // a rotated chat may still hold copies of the deleted edits
+            return Ok(vec![original]);
+        }
+        // only entries signed by the original author count as edits, or
+        // copies of them signed by the creator that rotated the chat
+        let mut edits = BTreeMap::new();
+        for signer in signers(id, creator) {
+            let query = Query::author(signer).key_prefix(id.edits_prefix()).build();
+            let mut entries = Box::pin(self.chat.get_many(query).await?);
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                let key = String::from_utf8_lossy(entry.key()).into_owned();
+                if edits.contains_key(&key) {
+                    continue;
+                }
+                if let Some(edit) =
+                    read_envelope(&iroh.blobs, &key, signer, creator, entry.content_hash()).await
+                {
+                    edits.insert(key, edit);
+                }
+            }
+        }
+        let mut versions = vec![original];
+        // edit keys start with a fixed width timestamp, so key order is send order
+        versions.extend(edits.into_values());
+        Ok(versions)
+    }
+
+    /// Reads the message `id` back from the doc, e.g. to show the message
+    /// a reply quotes. Returns `None` if it has not been synced (yet).
+    pub async fn get_message(
+        &self,
+        iroh: Arc<Iroh>,
+        id: MessageId,
+    ) -> Result<Option<Envelope>, ChatError> {
+        let creator = self.creator(&iroh).await?;
+        self.find_message(&iroh, creator, id).await
+    }
+
+    async fn find_message(
+        &self,
+        iroh: &Iroh,
+        creator: Option<AuthorId>,
+        id: MessageId,
+    ) -> Result<Option<Envelope>, ChatError> {
+        let Some(entry) = self.locate(id, creator).await? else {
+            return Ok(None);
+        };
+        let key = String::from_utf8_lossy(entry.key()).into_owned();
+        Ok(read_envelope(
+            &iroh.blobs,
+            &key,
+            entry.author(),
+            creator,
+            entry.content_hash(),
+        )
+        .await)
+    }
+
+    /// Finds the entry of the message `id`, which is either in the main
+    /// timeline or in one of the threads. The entry of its author wins over
+    /// a copy `creator` made when rotating the chat, so a tombstone written
+    /// after the rotation is found rather than the copied message.
+    async fn locate(
+        &self,
+        id: MessageId,
+        creator: Option<AuthorId>,
+    ) -> Result<Option<Entry>, ChatError> {
+        for signer in signers(id, creator) {
+            let query = Query::author(signer).key_exact(id.key()).build();
+            if let Some(entry) = self.chat.get_one(query).await? {
+                return Ok(Some(entry));
+            }
+            // thread keys don't start with the id, look through the threads
+            // the signer posted in
+            let query = Query::author(signer)
+                .key_prefix(MessageId::THREAD_KEY_PREFIX)
+                .build();
+            let mut entries = Box::pin(self.chat.get_many(query).await?);
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                let key = String::from_utf8_lossy(entry.key());
+                if matches!(MessageId::from_thread_key(&key), Some((_, found)) if found == id) {
+                    return Ok(Some(entry));
+                }
+            }
         }
+        Ok(None)
     }
 
     pub async fn set_author_name(
@@ -49,67 +433,713 @@ impl ChatClient {
         author: AuthorId,
         name: String,
     ) -> Result<(), ChatError> {
-        let _a = self
-            .chat
+        self.check_writable()?;
+        self.chat
             .set_bytes(author, author.to_string(), name.clone())
-            .await;
+            .await?;
         //let _ = self.send_message(author, Message::AuthorMessage { author , content: name }).await;
         Ok(())
     }
 
+    /// Returns the name `author` set for themselves, if any.
     pub async fn get_author_name(
         &mut self,
         author: AuthorId,
         iroh: Arc<Iroh>,
-    ) -> Result<String, AuthorId> {
+    ) -> Result<Option<String>, ChatError> {
         let blobs = iroh.blobs.clone();
         if let Some(b) = self
             .chat
-            .get_one(Query::key_exact(author.to_string()))
-            .await
-            .unwrap()
+            .get_one(Query::author(author).key_exact(author.to_string()).build())
+            .await?
         {
-            let name = blobs.read_to_bytes(b.content_hash()).await.unwrap();
-            let name = String::from_utf8(name.to_vec()).unwrap();
-            return Ok(name);
+            let name = blobs.read_to_bytes(b.content_hash()).await?;
+            let name =
+                String::from_utf8(name.to_vec()).map_err(|e| ChatError::Decode(e.to_string()))?;
+            return Ok(Some(name));
         };
 
-        Err(author)
+        Ok(None)
+    }
+
+    /// Whether this node only holds a read-only ticket for the chat and
+    /// can't post to it.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.capability, CapabilityKind::Read)
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<(), ChatError> {
+        if self.is_read_only() {
+            return Err(ChatError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// The namespace of the chat document, pass it to [`Iroh::open_chat`]
+    /// to reopen the chat later.
+    pub fn id(&self) -> NamespaceId {
+        self.chat.id()
+    }
+
+    /// Send time of the newest message outside of threads, in microseconds
+    /// since the unix epoch. `None` for chats without messages.
+    pub async fn last_activity(&self) -> Result<Option<u64>, ChatError> {
+        let query = Query::key_prefix(MessageId::KEY_PREFIX)
+            .sort_by(SortBy::KeyAuthor, SortDirection::Desc)
+            .limit(1)
+            .build();
+        let Some(entry) = self.chat.get_one(query).await? else {
+            return Ok(None);
+        };
+        let key = String::from_utf8_lossy(entry.key());
+        Ok(MessageId::from_key(&key).map(|id| id.timestamp))
+    }
+
+    /// Hashes of all blobs the chat refers to: entry contents, attachments,
+    /// thumbnails and avatars.
+    pub(crate) async fn referenced_blobs(&self, iroh: &Iroh) -> Result<HashSet<Hash>, ChatError> {
+        let creator = self.creator(iroh).await?;
+        let mut entries = Box::pin(self.chat.get_many(Query::all().build()).await?);
+        let mut hashes = HashSet::new();
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            hashes.insert(entry.content_hash());
+            let key = String::from_utf8_lossy(entry.key()).into_owned();
+            if key == AVATAR_KEY {
+                if let Ok(avatar) = iroh.blobs.read_to_bytes(entry.content_hash()).await {
+                    hashes.extend(
+                        std::str::from_utf8(&avatar)
+                            .ok()
+                            .and_then(|hash| hash.parse::<Hash>().ok()),
+                    );
+                }
+                continue;
+            }
+            let Some(envelope) = read_envelope(
+                &iroh.blobs,
+                &key,
+                entry.author(),
+                creator,
+                entry.content_hash(),
+            )
+            .await
+            else {
+                continue;
+            };
+            hashes.extend(envelope.thumbnail());
+            if let Message::Attachment { hash, .. } = envelope.message {
+                hashes.insert(hash);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Copies the messages, thread messages and edits of `from` into this
+    /// chat. The copies are signed by `author` and marked as migrated, so
+    /// they keep their original ids and authors. Reactions and display
+    /// names are not copied.
+    pub(crate) async fn copy_history(
+        &mut self,
+        iroh: &Iroh,
+        author: AuthorId,
+        from: &ChatClient,
+    ) -> Result<(), ChatError> {
+        let creator = from.creator(iroh).await?;
+        let mut entries = Box::pin(from.chat.get_many(Query::all().build()).await?);
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let key = String::from_utf8_lossy(entry.key()).into_owned();
+            let copied = [MessageId::KEY_PREFIX, MessageId::THREAD_KEY_PREFIX, "edit/"]
+                .iter()
+                .any(|prefix| key.starts_with(prefix));
+            if !copied {
+                continue;
+            }
+            let Some(envelope) = read_envelope(
+                &iroh.blobs,
+                &key,
+                entry.author(),
+                creator,
+                entry.content_hash(),
+            )
+            .await
+            else {
+                continue;
+            };
+            let bytes = envelope
+                .with_metadata(MIGRATED_METADATA, author.to_string())
+                .encode()?;
+            self.chat.set_bytes(author, key, bytes).await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the subscription and closes the chat document. Streams
+    /// returned by [`ChatClient::events`] should be dropped before.
+    pub async fn close(mut self) -> Result<(), ChatError> {
+        self.sub.take();
+        self.chat.close().await?;
+        Ok(())
     }
 }
 
+/// Errors returned by the chat library.
 #[derive(Debug)]
 pub enum ChatError {
-    SendError,
+    /// A message could not be encoded.
+    Serialization(bincode::Error),
+    /// An entry or blob holds something that can't be decoded.
+    Decode(String),
+    /// Reading from or writing to the local docs or blobs store failed.
+    Storage(anyhow::Error),
+    /// Connecting to or transferring from other nodes failed.
+    Network(anyhow::Error),
+    /// A chat ticket is malformed.
+    InvalidTicket(String),
+    /// The ticket is a valid ticket of another kind, e.g. a blob ticket.
+    WrongTicketKind(String),
+    /// A QR code could not be rendered, or no QR code was found in an
+    /// image.
+    QrCode(String),
+    /// The doc subscription could not be opened or has ended.
+    SubscriptionClosed,
+    /// The requested chat, message or blob is not known locally.
+    NotFound,
+    /// The author is not allowed to do this.
+    PermissionDenied,
+    /// The chat was joined with a read-only ticket.
+    ReadOnly,
+    /// The message is not an attachment.
+    NotAnAttachment,
+    /// The transfer was cancelled through its [`CancelToken`].
+    ///
+    /// [`CancelToken`]: crate::attachment::CancelToken
+    Cancelled,
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Serialization(e) => write!(f, "failed to encode message: {}", e),
+            Self::Decode(e) => write!(f, "failed to decode entry: {}", e),
+            Self::Storage(e) => write!(f, "storage error: {}", e),
+            Self::Network(e) => write!(f, "network error: {}", e),
+            Self::InvalidTicket(e) => write!(f, "invalid ticket: {}", e),
+            Self::WrongTicketKind(kind) => {
+                write!(f, "expected a chat ticket but got a {} ticket", kind)
+            }
+            Self::QrCode(e) => write!(f, "QR code error: {}", e),
+            Self::SubscriptionClosed => write!(f, "chat subscription closed"),
+            Self::NotFound => write!(f, "not found"),
+            Self::PermissionDenied => write!(f, "permission denied"),
+            Self::ReadOnly => write!(f, "this chat is read-only"),
+            Self::NotAnAttachment => write!(f, "message is not an attachment"),
+            Self::Cancelled => write!(f, "transfer cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Serialization(e) => Some(e),
+            Self::Storage(e) | Self::Network(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<bincode::Error> for ChatError {
+    fn from(e: bincode::Error) -> Self {
+        Self::Serialization(e)
+    }
+}
+
+/// The docs and blobs clients report everything as [`anyhow::Error`], so
+/// unless mapped explicitly those are local store errors.
+impl From<anyhow::Error> for ChatError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Storage(e)
+    }
+}
+
+/// A message read back from the chat document.
+#[derive(Debug)]
+pub struct StoredMessage {
+    /// doc key of the entry, usable as a pagination cursor
+    pub key: String,
+    pub envelope: Envelope,
+    /// the most recent edit of the message, if any
+    pub edit: Option<Envelope>,
+}
+
+/// A thread of a chat, see [`ChatClient::threads`].
+#[derive(Debug, Clone)]
+pub struct ThreadSummary {
+    /// the message that started the thread
+    pub root: MessageId,
+    /// number of messages posted in the thread
+    pub replies: usize,
+    /// timestamp of the latest message in the thread, in microseconds
+    pub last_activity: u64,
 }
 
 impl ChatClient {
-    pub async fn message_receiver_loop(&mut self, iroh: Arc<Iroh>) -> Result<Message, ChatError> {
-        let blobs = iroh.blobs.clone();
-        while let Ok(e) = self.sub.try_next().await {
-            if let Some(LiveEvent::InsertRemote { entry, .. }) = e {
-                let message_content = blobs.read_to_bytes(entry.content_hash()).await;
-                match message_content {
-                    Ok(a) => {
-                        if let Ok(a) = bincode::deserialize(&a) {
-                            return Ok(a);
-                        }
+    /// Returns the `n` most recent messages, oldest first.
+    pub async fn history_latest(
+        &self,
+        iroh: Arc<Iroh>,
+        n: usize,
+    ) -> Result<Vec<StoredMessage>, ChatError> {
+        self.history_page(
+            iroh,
+            MessageId::KEY_PREFIX,
+            SortDirection::Desc,
+            |_| true,
+            n,
+        )
+        .await
+    }
+
+    /// Returns up to `n` messages stored before `cursor`, oldest first.
+    pub async fn history_before(
+        &self,
+        iroh: Arc<Iroh>,
+        cursor: &str,
+        n: usize,
+    ) -> Result<Vec<StoredMessage>, ChatError> {
+        self.history_page(
+            iroh,
+            MessageId::KEY_PREFIX,
+            SortDirection::Desc,
+            |key| key < cursor,
+            n,
+        )
+        .await
+    }
+
+    /// Returns up to `n` messages stored after `cursor`, oldest first.
+    pub async fn history_after(
+        &self,
+        iroh: Arc<Iroh>,
+        cursor: &str,
+        n: usize,
+    ) -> Result<Vec<StoredMessage>, ChatError> {
+        self.history_page(
+            iroh,
+            MessageId::KEY_PREFIX,
+            SortDirection::Asc,
+            |key| key > cursor,
+            n,
+        )
+        .await
+    }
+
+    /// Returns the threads of this chat, most recently active first.
+    pub async fn threads(&self) -> Result<Vec<ThreadSummary>, ChatError> {
+        let query = Query::key_prefix(MessageId::THREAD_KEY_PREFIX).build();
+        let mut entries = Box::pin(self.chat.get_many(query).await?);
+        let mut threads: BTreeMap<MessageId, ThreadSummary> = BTreeMap::new();
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let Some((root, id)) =
+                MessageId::from_thread_key(&String::from_utf8_lossy(entry.key()))
+            else {
+                continue;
+            };
+            let thread = threads.entry(root).or_insert(ThreadSummary {
+                root,
+                replies: 0,
+                last_activity: 0,
+            });
+            thread.replies += 1;
+            thread.last_activity = thread.last_activity.max(id.timestamp);
+        }
+        let mut threads: Vec<_> = threads.into_values().collect();
+        threads.sort_by_key(|thread| Reverse(thread.last_activity));
+        Ok(threads)
+    }
+
+    /// Returns up to `n` of the most recent messages of the thread started
+    /// by `root`, oldest first.
+    pub async fn thread_messages(
+        &self,
+        iroh: Arc<Iroh>,
+        root: MessageId,
+        n: usize,
+    ) -> Result<Vec<StoredMessage>, ChatError> {
+        self.history_page(
+            iroh,
+            &root.thread_prefix(),
+            SortDirection::Desc,
+            |_| true,
+            n,
+        )
+        .await
+    }
+
+    /// Walks the message entries in key order and collects the first `n`
+    /// accepted by `filter`. Message ids start with a fixed width
+    /// timestamp, so key order is send order.
+    async fn history_page(
+        &self,
+        iroh: Arc<Iroh>,
+        prefix: &str,
+        direction: SortDirection,
+        filter: impl Fn(&str) -> bool,
+        n: usize,
+    ) -> Result<Vec<StoredMessage>, ChatError> {
+        let newest_first = matches!(direction, SortDirection::Desc);
+        let creator = self.creator(&iroh).await?;
+        let query = Query::key_prefix(prefix)
+            .sort_by(SortBy::KeyAuthor, direction)
+            .build();
+        let mut entries = Box::pin(self.chat.get_many(query).await?);
+        let mut page: Vec<StoredMessage> = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let key = String::from_utf8_lossy(entry.key()).into_owned();
+            if !filter(&key) {
+                continue;
+            }
+            let duplicate = page.last().is_some_and(|last| last.key == key);
+            if !duplicate && page.len() >= n {
+                break;
+            }
+            let Some(envelope) = read_envelope(
+                &iroh.blobs,
+                &key,
+                entry.author(),
+                creator,
+                entry.content_hash(),
+            )
+            .await
+            else {
+                continue;
+            };
+            if duplicate {
+                // a copy made when the chat was rotated next to an entry
+                // its author wrote afterwards, e.g. a tombstone; the
+                // author's own entry wins
+                if envelope.migrated_by().is_some() {
+                    continue;
+                }
+                page.pop();
+            }
+            let edit = self.latest_version(&iroh, creator, envelope.id).await?;
+            page.push(StoredMessage {
+                key,
+                envelope,
+                edit,
+            });
+        }
+        if newest_first {
+            page.reverse();
+        }
+        self.prefetch_thumbnails(&iroh, &page).await;
+        Ok(page)
+    }
+}
+
+impl ChatClient {
+    /// Returns the stream of events for this chat.
+    ///
+    /// The first call hands out the subscription opened when the chat was
+    /// created or joined, so nothing inserted in between is missed; later
+    /// calls open a fresh subscription. The stream ends when the
+    /// subscription does.
+    ///
+    /// Remote entries whose content has not arrived yet are held back and
+    /// delivered once their content is ready, either through the sync
+    /// engine or through an explicit download from the peer that sent them.
+    pub async fn events(&mut self, iroh: Arc<Iroh>) -> Result<ChatEvents, ChatError> {
+        let sub: SubC = match self.sub.take() {
+            Some(sub) => sub,
+            None => Box::pin(
+                self.chat
+                    .subscribe()
+                    .await
+                    .map_err(|_| ChatError::SubscriptionClosed)?,
+            ),
+        };
+        Ok(Box::pin(event_stream(
+            sub,
+            self.chat.clone(),
+            iroh.blobs.clone(),
+        )))
+    }
+
+    /// Returns the stream of messages posted in the thread started by
+    /// `root`. Uses a subscription of its own, so it can run next to
+    /// [`ChatClient::events`].
+    pub async fn thread_events(
+        &self,
+        iroh: Arc<Iroh>,
+        root: MessageId,
+    ) -> Result<ChatEvents, ChatError> {
+        let sub: SubC = Box::pin(
+            self.chat
+                .subscribe()
+                .await
+                .map_err(|_| ChatError::SubscriptionClosed)?,
+        );
+        let events = event_stream(sub, self.chat.clone(), iroh.blobs.clone());
+        let events = events.filter(move |event| match event {
+            ChatEvent::MessageReceived { envelope, .. }
+            | ChatEvent::MessageSent { envelope, .. } => envelope.thread() == Some(root),
+            _ => false,
+        });
+        Ok(Box::pin(events))
+    }
+}
+
+fn event_stream(
+    sub: SubC,
+    chat: ChatC,
+    blobs: BlobsClient,
+) -> impl Stream<Item = ChatEvent> + Send + 'static {
+    let state = EventState::new(sub, chat, blobs);
+    futures_lite::stream::unfold(state, |mut state| async move {
+        state.next().await.map(|e| (e, state))
+    })
+}
+
+/// A remote entry whose content was not available when it was inserted.
+struct PendingEntry {
+    from: PublicKey,
+    author: AuthorId,
+    key: String,
+}
+
+struct EventState {
+    sub: SubC,
+    chat: ChatC,
+    blobs: BlobsClient,
+    /// the chat's creator, whose copies of rotated messages are trusted
+    creator: Option<AuthorId>,
+    /// entries waiting for their content, by content hash
+    pending: HashMap<Hash, Vec<PendingEntry>>,
+    /// hashes we already started an explicit download for
+    downloading: HashSet<Hash>,
+    /// finished explicit downloads and whether they succeeded
+    downloaded_tx: mpsc::UnboundedSender<(Hash, bool)>,
+    downloaded_rx: mpsc::UnboundedReceiver<(Hash, bool)>,
+    thumbnails_tx: mpsc::UnboundedSender<(MessageId, Hash)>,
+    thumbnails_rx: mpsc::UnboundedReceiver<(MessageId, Hash)>,
+    queue: VecDeque<ChatEvent>,
+}
+
+impl EventState {
+    fn new(sub: SubC, chat: ChatC, blobs: BlobsClient) -> Self {
+        let (downloaded_tx, downloaded_rx) = mpsc::unbounded_channel();
+        let (thumbnails_tx, thumbnails_rx) = mpsc::unbounded_channel();
+        Self {
+            sub,
+            chat,
+            blobs,
+            creator: None,
+            pending: HashMap::new(),
+            downloading: HashSet::new(),
+            downloaded_tx,
+            downloaded_rx,
+            thumbnails_tx,
+            thumbnails_rx,
+            queue: VecDeque::new(),
+        }
+    }
+
+    async fn next(&mut self) -> Option<ChatEvent> {
+        loop {
+            if let Some(event) = self.queue.pop_front() {
+                return Some(event);
+            }
+            tokio::select! {
+                Some((hash, ok)) = self.downloaded_rx.recv() => {
+                    // failed downloads stay pending and are retried on the
+                    // next PendingContentReady
+                    self.downloading.remove(&hash);
+                    if ok {
+                        self.deliver(hash).await;
                     }
-                    Err(_) => {
-                        // may still be syncing so
-                        for _ in 0..3 {
-                            thread::sleep(Duration::from_secs(1));
-                            let message_content = blobs.read_to_bytes(entry.content_hash()).await;
-                            if let Ok(a) = message_content {
-                                if let Ok(a) = bincode::deserialize(&a) {
-                                    return Ok(a);
-                                }
+                }
+                Some((id, hash)) = self.thumbnails_rx.recv() => {
+                    self.queue.push_back(ChatEvent::ThumbnailReady { id, hash });
+                }
+                event = self.sub.next() => match event {
+                    Some(Ok(event)) => self.handle(event).await,
+                    Some(Err(_)) => {}
+                    None => return None,
+                },
+            }
+        }
+    }
+
+    async fn handle(&mut self, event: LiveEvent) {
+        match event {
+            LiveEvent::InsertLocal { entry } => {
+                let key = String::from_utf8_lossy(entry.key()).into_owned();
+                let author = entry.author();
+                let envelope = match self.blobs.read_to_bytes(entry.content_hash()).await {
+                    Ok(bytes) => self.decode(&key, author, &bytes).await,
+                    Err(_) => None,
+                };
+                self.queue.push_back(match envelope {
+                    Some(envelope) => ChatEvent::MessageSent { key, envelope },
+                    None => ChatEvent::EntryChanged {
+                        from: None,
+                        key,
+                        author,
+                    },
+                });
+            }
+            LiveEvent::InsertRemote { from, entry, .. } => {
+                let hash = entry.content_hash();
+                let pending = PendingEntry {
+                    from,
+                    author: entry.author(),
+                    key: String::from_utf8_lossy(entry.key()).into_owned(),
+                };
+                match self.blobs.read_to_bytes(hash).await {
+                    Ok(bytes) => self.push_received(pending, &bytes).await,
+                    // content is still syncing, hold the entry until it is ready
+                    Err(_) => self.pending.entry(hash).or_default().push(pending),
+                }
+            }
+            LiveEvent::ContentReady { hash } => {
+                self.deliver(hash).await;
+                self.queue.push_back(ChatEvent::ContentReady(hash));
+            }
+            LiveEvent::PendingContentReady => {
+                // whatever is still missing was not fetched by the sync
+                // engine, ask the peers that sent us the entries directly
+                for (hash, entries) in self.pending.iter() {
+                    if self.downloading.insert(*hash) {
+                        let mut peers = Vec::new();
+                        for entry in entries {
+                            if !peers.contains(&entry.from) {
+                                peers.push(entry.from);
                             }
                         }
+                        self.spawn_download(*hash, peers);
                     }
                 }
+                self.queue.push_back(ChatEvent::PendingContentReady);
             }
+            LiveEvent::SyncFinished(sync) => self.queue.push_back(ChatEvent::SyncFinished {
+                peer: sync.peer,
+                result: sync.result.map(|_| ()),
+            }),
+            LiveEvent::NeighborUp(peer) => self.queue.push_back(ChatEvent::NeighborUp(peer)),
+            LiveEvent::NeighborDown(peer) => self.queue.push_back(ChatEvent::NeighborDown(peer)),
         }
-        Err(ChatError::SendError)
     }
+
+    /// Delivers every pending entry waiting for `hash`.
+    async fn deliver(&mut self, hash: Hash) {
+        let Some(entries) = self.pending.remove(&hash) else {
+            return;
+        };
+        match self.blobs.read_to_bytes(hash).await {
+            Ok(bytes) => {
+                for entry in entries {
+                    self.push_received(entry, &bytes).await;
+                }
+            }
+            Err(_) => {
+                self.pending.insert(hash, entries);
+            }
+        }
+    }
+
+    async fn push_received(&mut self, entry: PendingEntry, bytes: &[u8]) {
+        if let Some(envelope) = self.decode(&entry.key, entry.author, bytes).await {
+            // thumbnails are small, fetch them right away unless the sender
+            // claims otherwise; the fetch checks the size it actually gets.
+            // The attachment itself is only fetched on demand
+            let small = envelope
+                .thumbnail_size()
+                .is_some_and(|size| size <= MAX_THUMBNAIL_SIZE);
+            if let Some(thumbnail) = envelope.thumbnail().filter(|_| small) {
+                let mut peers: Vec<_> = envelope.uploader().into_iter().collect();
+                if !peers.contains(&entry.from) {
+                    peers.push(entry.from);
+                }
+                self.spawn_thumbnail_download(envelope.id, thumbnail, peers);
+            }
+            self.queue.push_back(ChatEvent::MessageReceived {
+                from: entry.from,
+                key: entry.key,
+                envelope,
+            });
+        } else {
+            self.queue.push_back(ChatEvent::EntryChanged {
+                from: Some(entry.from),
+                key: entry.key,
+                author: entry.author,
+            });
+        }
+    }
+
+    /// Decodes the content of the entry `key`. Copies from a rotated chat
+    /// may arrive before the entry naming the creator that made them, so
+    /// the creator is looked up again while it is not known.
+    async fn decode(&mut self, key: &str, author: AuthorId, bytes: &[u8]) -> Option<Envelope> {
+        if let Some(envelope) = Envelope::decode(key, author, self.creator, bytes) {
+            return Some(envelope);
+        }
+        if self.creator.is_some() {
+            return None;
+        }
+        self.creator = chat_creator(&self.chat, &self.blobs).await.ok().flatten();
+        Envelope::decode(key, author, self.creator, bytes)
+    }
+
+    fn spawn_thumbnail_download(&self, id: MessageId, hash: Hash, peers: Vec<PublicKey>) {
+        let blobs = self.blobs.clone();
+        let ready = self.thumbnails_tx.clone();
+        tokio::spawn(async move {
+            if fetch_thumbnail(&blobs, hash, peers).await {
+                let _ = ready.send((id, hash));
+            }
+        });
+    }
+
+    /// Downloads `hash` from the first of `peers` that has it.
+    fn spawn_download(&self, hash: Hash, peers: Vec<PublicKey>) {
+        let blobs = self.blobs.clone();
+        let downloaded = self.downloaded_tx.clone();
+        tokio::spawn(async move {
+            for from in peers {
+                let Ok(progress) = blobs.download(hash, NodeAddr::new(from)).await else {
+                    continue;
+                };
+                if progress.finish().await.is_ok() {
+                    let _ = downloaded.send((hash, true));
+                    return;
+                }
+            }
+            let _ = downloaded.send((hash, false));
+        });
+    }
+}
+
+/// Reads and decodes the entry `key` signed by `author`, see
+/// [`Envelope::decode`] for `migrator`.
+pub(crate) async fn read_envelope(
+    blobs: &BlobsClient,
+    key: &str,
+    author: AuthorId,
+    migrator: Option<AuthorId>,
+    hash: Hash,
+) -> Option<Envelope> {
+    let bytes = blobs.read_to_bytes(hash).await.ok()?;
+    Envelope::decode(key, author, migrator, &bytes)
+}
+
+/// The authors whose entries may hold the message `id`: its author, and
+/// the creator that copied it when rotating the chat.
+fn signers(id: MessageId, creator: Option<AuthorId>) -> Vec<AuthorId> {
+    let mut signers = vec![id.author];
+    signers.extend(creator.filter(|creator| *creator != id.author));
+    signers
 }
@@ -0,0 +1,95 @@
+use std::io::Cursor;
+
+use image::{ImageFormat, Luma};
+use qrcode::{QrCode, render::unicode};
+
+use crate::client::ChatError;
+
+/// Renders `ticket` as a QR code made of unicode half blocks, for printing
+/// to a terminal.
+pub fn qr_terminal(ticket: &str) -> Result<String, ChatError> {
+    let code = qr_code(ticket)?;
+    // terminals are mostly light text on a dark background, so the colors
+    // are swapped to keep the code readable for scanners
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+/// Renders `ticket` as a QR code PNG image.
+pub fn qr_png(ticket: &str) -> Result<Vec<u8>, ChatError> {
+    let image = qr_code(ticket)?.render::<Luma<u8>>().build();
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| ChatError::QrCode(e.to_string()))?;
+    Ok(png)
+}
+
+/// Reads the ticket from a picture of its QR code.
+pub fn ticket_from_qr(image: &[u8]) -> Result<String, ChatError> {
+    let image = image::load_from_memory(image)
+        .map_err(|e| ChatError::QrCode(e.to_string()))?
+        .to_luma8();
+    let mut image = rqrr::PreparedImage::prepare(image);
+    image
+        .detect_grids()
+        .iter()
+        .find_map(|grid| grid.decode().ok())
+        .map(|(_, content)| normalize_ticket(&content))
+        .ok_or_else(|| ChatError::QrCode("no QR code found in the image".to_string()))
+}
+
+/// Turns a pasted or scanned ticket back into its canonical form:
+/// whitespace, e.g. from line breaks, is dropped and upper case letters
+/// from QR codes are lowered.
+pub fn normalize_ticket(input: &str) -> String {
+    input
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Tickets are base32, which fits the compact alphanumeric QR mode once
+/// upper cased.
+fn qr_code(ticket: &str) -> Result<QrCode, ChatError> {
+    QrCode::new(ticket.to_ascii_uppercase()).map_err(|e| ChatError::QrCode(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICKET: &str = "docaaacbxyzz7ucqlsqzlrrvmbw2gcjeqpqw4lo5jn5l3m4ostrbq";
+
+    #[test]
+    fn normalizes_pasted_and_scanned_tickets() {
+        assert_eq!(normalize_ticket(TICKET), TICKET);
+        assert_eq!(normalize_ticket(&TICKET.to_ascii_uppercase()), TICKET);
+        let wrapped = format!(" {}\n{} ", &TICKET[..20], &TICKET[20..]);
+        assert_eq!(normalize_ticket(&wrapped), TICKET);
+    }
+
+    #[test]
+    fn qr_png_round_trip() {
+        let png = qr_png(TICKET).unwrap();
+        assert_eq!(ticket_from_qr(&png).unwrap(), TICKET);
+    }
+
+    #[test]
+    fn image_without_qr_code() {
+        let blank = image::GrayImage::from_pixel(64, 64, Luma([255]));
+        let mut png = Vec::new();
+        blank
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        assert!(matches!(ticket_from_qr(&png), Err(ChatError::QrCode(_))));
+        assert!(matches!(
+            ticket_from_qr(b"not an image"),
+            Err(ChatError::QrCode(_))
+        ));
+    }
+}
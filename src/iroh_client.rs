@@ -1,16 +1,36 @@
-use std::{path::PathBuf, pin::Pin, str::FromStr, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddrV4},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use iroh_blobs::util::local_pool::LocalPool;
+use futures_lite::StreamExt;
+use iroh::{
+    PublicKey, RelayMode, SecretKey,
+    protocol::{Router, RouterBuilder},
+};
+use iroh_blobs::{Hash, net_protocol::Blobs, util::local_pool::LocalPool};
 use iroh_docs::{
-    AuthorId, DocTicket,
+    Author, AuthorId, CapabilityKind, DocTicket, NamespaceId,
+    engine::LiveEvent,
     rpc::{AddrInfoOptions, client::docs::ShareMode},
 };
-use iroh_gossip::RpcClient;
+use iroh_gossip::{
+    RpcClient,
+    net::{Event as GossipNetEvent, Gossip, GossipEvent},
+};
 use quic_rpc::transport::flume::FlumeConnector;
 
 use crate::{
-    client::{ChatC, ChatClient},
-    message::Message,
+    client::{ChatC, ChatClient, ChatError},
+    invite::normalize_ticket,
+    message::{Envelope, Message},
+    metadata::{ChatKind, ChatMetadata, pin_creator, unpin_creator},
 };
 
 pub type BlobsClient = iroh_blobs::rpc::client::blobs::Client<
@@ -22,101 +42,770 @@ pub type DocsClient = iroh_docs::rpc::client::docs::Client<
 pub type GossipClient =
     RpcClient<FlumeConnector<iroh_gossip::rpc::proto::Response, iroh_gossip::rpc::proto::Request>>;
 
+/// How long [`Iroh::join_chat`] waits for each node in a ticket.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long [`Iroh::neighbors`] waits for the gossip swarm to report.
+const NEIGHBORS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long [`Iroh::leave_chat`] waits for the leave announcement to go out.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone, Debug)]
 #[allow(dead_code)]
 pub struct Iroh {
     _local_pool: Arc<iroh_blobs::util::local_pool::LocalPool>,
-    router: iroh::protocol::Router,
+    router: Router,
     pub(crate) gossip: GossipClient,
     pub(crate) blobs: BlobsClient,
     pub(crate) docs: DocsClient,
     pub(crate) author: AuthorId,
+    /// Addresses put into the tickets this node shares.
+    addr_options: AddrInfoOptions,
 }
 
-impl Iroh {
-    pub async fn new(path: PathBuf) -> Result<Self, anyhow::Error> {
-        // create dir if it doesn't already exist
-        tokio::fs::create_dir_all(&path).await?;
+/// A chat stored on this node, as listed by [`Iroh::list_chats`].
+#[derive(Debug, Clone)]
+pub struct ChatSummary {
+    pub id: NamespaceId,
+    pub metadata: ChatMetadata,
+    /// send time of the newest message, see [`ChatClient::last_activity`]
+    pub last_activity: Option<u64>,
+}
+
+/// Where the blobs and docs stores keep their data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Storage {
+    /// On disk below the data directory.
+    #[default]
+    Persistent,
+    /// In memory, gone once the node is dropped.
+    Memory,
+}
+
+/// Where the node's secret key comes from.
+#[derive(Debug, Clone, Default)]
+pub enum SecretKeySource {
+    /// Loaded from `keypair` in the data directory, created on first use.
+    #[default]
+    DataDir,
+    /// Loaded from the given file, created on first use.
+    File(PathBuf),
+    /// The given key.
+    Key(SecretKey),
+    /// A fresh key on every start.
+    Generate,
+}
+
+/// Which author the node writes messages as.
+// only ever built once per node, not worth boxing the author
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Default)]
+pub enum AuthorSource {
+    /// The default author of the docs store.
+    #[default]
+    Default,
+    /// A newly created author.
+    Create,
+    /// The given author, imported into the docs store.
+    Import(Author),
+}
+
+/// Configures and starts an [`Iroh`] node.
+///
+/// The defaults match [`Iroh::new`]: persistent storage in `.iroh-dir`, n0
+/// discovery, the default relays and the default author.
+#[derive(Debug, Clone)]
+pub struct IrohBuilder {
+    data_dir: PathBuf,
+    storage: Storage,
+    discovery_n0: bool,
+    discovery_local_network: bool,
+    relay_mode: RelayMode,
+    bind_addr: Option<SocketAddrV4>,
+    secret_key: SecretKeySource,
+    author: AuthorSource,
+    addr_options: AddrInfoOptions,
+}
+
+impl Default for IrohBuilder {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from(".iroh-dir"),
+            storage: Storage::default(),
+            discovery_n0: true,
+            discovery_local_network: false,
+            relay_mode: RelayMode::Default,
+            bind_addr: None,
+            secret_key: SecretKeySource::default(),
+            author: AuthorSource::default(),
+            addr_options: AddrInfoOptions::RelayAndAddresses,
+        }
+    }
+}
+
+impl IrohBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Directory for the stores and the secret key.
+    pub fn data_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.data_dir = path.into();
+        self
+    }
+
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Whether to publish and resolve node addresses through n0's DNS
+    /// servers.
+    pub fn discovery_n0(mut self, enable: bool) -> Self {
+        self.discovery_n0 = enable;
+        self
+    }
+
+    /// Whether to find nodes on the local network via mDNS.
+    pub fn discovery_local_network(mut self, enable: bool) -> Self {
+        self.discovery_local_network = enable;
+        self
+    }
+
+    pub fn relay_mode(mut self, relay_mode: RelayMode) -> Self {
+        self.relay_mode = relay_mode;
+        self
+    }
+
+    /// Binds the endpoint to `addr` instead of a random port on all
+    /// interfaces.
+    pub fn bind_addr(mut self, addr: SocketAddrV4) -> Self {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    /// Binds the endpoint to `port` on all interfaces.
+    pub fn port(self, port: u16) -> Self {
+        self.bind_addr(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+    }
+
+    /// Which addresses go into shared tickets.
+    pub fn ticket_addrs(mut self, options: AddrInfoOptions) -> Self {
+        self.addr_options = options;
+        self
+    }
+
+    /// Runs without any public infrastructure: no relays, no n0 discovery,
+    /// peers are found via mDNS and tickets carry direct addresses only.
+    pub fn local_network_only(self) -> Self {
+        self.relay_mode(RelayMode::Disabled)
+            .discovery_n0(false)
+            .discovery_local_network(true)
+            .ticket_addrs(AddrInfoOptions::Addresses)
+    }
+
+    pub fn secret_key(mut self, source: SecretKeySource) -> Self {
+        self.secret_key = source;
+        self
+    }
+
+    pub fn author(mut self, source: AuthorSource) -> Self {
+        self.author = source;
+        self
+    }
 
-        let key = iroh_blobs::util::fs::load_secret_key(path.clone().join("keypair")).await?;
+    pub async fn build(self) -> Result<Iroh, ChatError> {
+        let key_path = match &self.secret_key {
+            SecretKeySource::DataDir => Some(self.data_dir.join("keypair")),
+            SecretKeySource::File(path) => Some(path.clone()),
+            SecretKeySource::Key(_) | SecretKeySource::Generate => None,
+        };
+        // create the dirs if they don't already exist
+        let mut dirs = Vec::new();
+        if self.storage == Storage::Persistent {
+            dirs.push(self.data_dir.as_path());
+        }
+        if let Some(dir) = key_path.as_deref().and_then(Path::parent) {
+            dirs.push(dir);
+        }
+        for dir in dirs {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| ChatError::Storage(e.into()))?;
+        }
 
         // local thread pool manager for blobs
         let local_pool = LocalPool::default();
 
         // create endpoint
-        let endpoint = iroh::Endpoint::builder()
-            .discovery_n0()
-            .secret_key(key)
-            .bind()
-            .await?;
+        let mut endpoint = iroh::Endpoint::builder().relay_mode(self.relay_mode);
+        if self.discovery_n0 {
+            endpoint = endpoint.discovery_n0();
+        }
+        if self.discovery_local_network {
+            endpoint = endpoint.discovery_local_network();
+        }
+        if let Some(addr) = self.bind_addr {
+            endpoint = endpoint.bind_addr_v4(addr);
+        }
+        match (self.secret_key, key_path) {
+            (_, Some(path)) => {
+                endpoint = endpoint.secret_key(iroh_blobs::util::fs::load_secret_key(path).await?)
+            }
+            (SecretKeySource::Key(key), _) => endpoint = endpoint.secret_key(key),
+            // the endpoint generates a key when none is set
+            _ => {}
+        }
+        let endpoint = endpoint.bind().await.map_err(ChatError::Network)?;
 
         // build the protocol router
-        let mut builder = iroh::protocol::Router::builder(endpoint);
+        let builder = iroh::protocol::Router::builder(endpoint);
 
         // add iroh gossip
         let gossip = iroh_gossip::net::Gossip::builder()
             .spawn(builder.endpoint().clone())
-            .await?;
+            .await
+            .map_err(|e| ChatError::Network(e.into()))?;
 
-        // add iroh blobs
-        let blobs = iroh_blobs::net_protocol::Blobs::persistent(&path)
-            .await?
-            .build(builder.endpoint());
-
-        // add docs
-        let docs = iroh_docs::protocol::Docs::persistent(path)
-            .spawn(&blobs, &gossip)
-            .await?;
+        // add iroh blobs and docs
+        let (router, blobs, docs) = match self.storage {
+            Storage::Persistent => {
+                let blobs = iroh_blobs::net_protocol::Blobs::persistent(&self.data_dir)
+                    .await?
+                    .build(builder.endpoint());
+                let docs = iroh_docs::protocol::Docs::persistent(self.data_dir);
+                spawn_router(builder, &gossip, blobs, docs).await?
+            }
+            Storage::Memory => {
+                let blobs = iroh_blobs::net_protocol::Blobs::memory().build(builder.endpoint());
+                let docs = iroh_docs::protocol::Docs::memory();
+                spawn_router(builder, &gossip, blobs, docs).await?
+            }
+        };
 
-        builder = builder
-            .accept(iroh_gossip::ALPN, Arc::new(gossip.clone()))
-            .accept(iroh_blobs::ALPN, blobs.clone())
-            .accept(iroh_docs::ALPN, Arc::new(docs.clone()));
-        let gc = gossip.client().clone();
+        let author = match self.author {
+            AuthorSource::Default => docs.authors().default().await?,
+            AuthorSource::Create => docs.authors().create().await?,
+            AuthorSource::Import(author) => {
+                let id = author.id();
+                docs.authors().import(author).await?;
+                id
+            }
+        };
 
-        Ok(Self {
+        Ok(Iroh {
             _local_pool: Arc::new(local_pool),
-            router: builder.spawn().await?,
-            gossip: gc,
-            blobs: blobs.client().clone(),
-            docs: docs.client().clone(),
-            author: docs.client().authors().default().await?,
+            router,
+            gossip: gossip.client().clone(),
+            blobs,
+            docs,
+            author,
+            addr_options: self.addr_options,
         })
     }
+}
+
+/// Spawns docs on top of `blobs` and `gossip` and starts the router
+/// accepting all three protocols.
+async fn spawn_router<S: iroh_blobs::store::Store>(
+    builder: RouterBuilder,
+    gossip: &Gossip,
+    blobs: Blobs<S>,
+    docs: iroh_docs::protocol::Builder,
+) -> Result<(Router, BlobsClient, DocsClient), ChatError> {
+    let docs = docs.spawn(&blobs, gossip).await?;
+    let router = builder
+        .accept(iroh_gossip::ALPN, Arc::new(gossip.clone()))
+        .accept(iroh_blobs::ALPN, blobs.clone())
+        .accept(iroh_docs::ALPN, Arc::new(docs.clone()))
+        .spawn()
+        .await
+        .map_err(ChatError::Network)?;
+    Ok((router, blobs.client().clone(), docs.client().clone()))
+}
+
+impl Iroh {
+    /// Starts a node with persistent storage in `path`, see [`IrohBuilder`]
+    /// for other configurations.
+    pub async fn new(path: PathBuf) -> Result<Self, ChatError> {
+        IrohBuilder::new().data_dir(path).build().await
+    }
 
-    pub async fn create_chat(&self) -> anyhow::Result<ChatClient> {
+    /// Starts a node that keeps nothing on disk: in-memory stores and a
+    /// freshly generated key. Any number of these can run in one process,
+    /// dropping the node discards all of its state.
+    pub async fn memory() -> Result<Self, ChatError> {
+        IrohBuilder::new()
+            .storage(Storage::Memory)
+            .secret_key(SecretKeySource::Generate)
+            .build()
+            .await
+    }
+
+    pub fn builder() -> IrohBuilder {
+        IrohBuilder::new()
+    }
+
+    /// The id other nodes reach this node by.
+    pub fn node_id(&self) -> PublicKey {
+        self.router.endpoint().node_id()
+    }
+
+    /// The nodes this node is currently connected to in the gossip swarm
+    /// of the chat `id`.
+    pub(crate) async fn neighbors(&self, id: NamespaceId) -> Vec<PublicKey> {
+        let Ok((_updates, events)) = self.gossip.subscribe(id, Vec::<PublicKey>::new()).await
+        else {
+            return Vec::new();
+        };
+        // a swarm we are part of reports its neighbors as the first event,
+        // otherwise nothing arrives
+        let mut events = std::pin::pin!(events);
+        match tokio::time::timeout(NEIGHBORS_TIMEOUT, events.next()).await {
+            Ok(Some(Ok(GossipNetEvent::Gossip(GossipEvent::Joined(neighbors))))) => neighbors,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Stops the node: the docs and blobs stores are flushed, open
+    /// connections are closed and the endpoint shuts down. Close the chat
+    /// clients first with [`ChatClient::close`].
+    pub async fn shutdown(&self) -> Result<(), ChatError> {
+        self.router.shutdown().await.map_err(ChatError::Network)
+    }
+
+    /// Creates a new chat with this node's author as its creator.
+    ///
+    /// Announcement channels only hand out read-only tickets, so the
+    /// creator is the only one who can post.
+    pub async fn create_chat(
+        &self,
+        kind: ChatKind,
+        title: Option<String>,
+        topic: Option<String>,
+    ) -> Result<ChatClient, ChatError> {
+        let mut client = self.new_chat(kind).await?;
+        client.set_creator(self.author, kind).await?;
+        pin_creator(&self.blobs, client.id(), self.author).await?;
+        if let Some(title) = title {
+            client.set_title(self.author, title).await?;
+        }
+        if let Some(topic) = topic {
+            client.set_topic(self.author, topic).await?;
+        }
+        Ok(client)
+    }
+
+    /// Creates the document of a new chat and announces it. Hand out
+    /// tickets for it with [`Iroh::ticket`].
+    async fn new_chat(&self, kind: ChatKind) -> Result<ChatClient, ChatError> {
         let a = self.clone();
         let temp_doc = a.docs.create().await?;
 
-        let ticket = temp_doc
-            .share(ShareMode::Write, AddrInfoOptions::RelayAndAddresses)
-            .await?;
+        let ticket = temp_doc.share(ShareMode::Write, a.addr_options).await?;
+        let read_ticket = temp_doc.share(ShareMode::Read, a.addr_options).await?;
 
-        let (chat, sub): (ChatC, _) = a.docs.import_and_subscribe(ticket.clone()).await.unwrap();
+        let (chat, sub): (ChatC, _) = a.docs.import_and_subscribe(ticket.clone()).await?;
         let sub = Pin::new(Box::new(sub));
+        // everyone can read the announcement, it must not carry write access
+        // to an announcement channel
+        let shared = match kind {
+            ChatKind::Group => &ticket,
+            ChatKind::Announcement => &read_ticket,
+        };
         let test: Vec<u8> =
-            bincode::serialize(&Message::set_ticket(a.author, ticket.to_string())).unwrap();
-        let _ = chat.set_bytes(a.author, "chat-ticket", test).await;
-        println!("share this ticket to your friend: {}", ticket);
-        Ok(ChatClient { chat, sub })
+            Envelope::new(a.author, Message::set_ticket(a.author, shared.to_string())).encode()?;
+        chat.set_bytes(a.author, "chat-ticket", test).await?;
+        let client = ChatClient {
+            chat,
+            sub: Some(sub),
+            capability: CapabilityKind::Write,
+        };
+        Ok(client)
     }
 
-    pub async fn join_chat(&self, ticket: String) -> Option<anyhow::Result<ChatClient>> {
-        let a = self.clone();
-        let Ok(doct) = DocTicket::from_str(ticket.as_str()) else {
-            return None;
-        };
-        let Ok((chat, sub)) = a.docs.import_and_subscribe(doct).await else {
-            return None;
+    /// Moves the chat into a new document, locking out everyone who only
+    /// holds tickets for the old one.
+    ///
+    /// History and metadata are copied over and the old chat gets a
+    /// [`Message::Moved`] marker. Members that already hold the new chat
+    /// follow the marker with [`Iroh::follow_move`], everyone else needs a
+    /// ticket from [`Iroh::ticket`] for the returned client. Only the
+    /// creator can rotate a chat.
+    pub async fn rotate_chat(&self, old: &mut ChatClient) -> Result<ChatClient, ChatError> {
+        old.check_writable()?;
+        let metadata = old.metadata(Arc::new(self.clone())).await?;
+        if metadata.creator != Some(self.author) {
+            return Err(ChatError::PermissionDenied);
+        }
+        let mut client = self.new_chat(metadata.kind).await?;
+        client.copy_metadata(self.author, &metadata).await?;
+        pin_creator(&self.blobs, client.id(), self.author).await?;
+        client.copy_history(self, self.author, old).await?;
+        old.send_message(self.author, Message::new_moved(self.author, client.id()))
+            .await?;
+        Ok(client)
+    }
+
+    /// Opens the chat `client` was moved to, if its creator rotated it and
+    /// this node holds a ticket for the new chat.
+    pub async fn follow_move(&self, client: &ChatClient) -> Result<Option<ChatClient>, ChatError> {
+        let Some(namespace) = client.moved_to(Arc::new(self.clone())).await? else {
+            return Ok(None);
         };
+        let creator = client.creator(self).await?;
+        match self.open_chat(namespace).await {
+            Ok(next) => {
+                // the marker was written by the creator, who stays the
+                // creator of the new chat
+                if let Some(creator) = creator {
+                    pin_creator(&self.blobs, namespace, creator).await?;
+                }
+                Ok(Some(next))
+            }
+            Err(ChatError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lists the chats stored on this node, most recently active first.
+    pub async fn list_chats(&self) -> Result<Vec<ChatSummary>, ChatError> {
+        let ids: Vec<_> = self.docs.list().await?.try_collect().await?;
+        let mut chats = Vec::new();
+        for (id, capability) in ids {
+            let Some(chat) = self.docs.open(id).await? else {
+                continue;
+            };
+            let client = ChatClient {
+                chat,
+                sub: None,
+                capability,
+            };
+            let metadata = client.metadata(Arc::new(self.clone())).await?;
+            let last_activity = client.last_activity().await?;
+            client.close().await?;
+            chats.push(ChatSummary {
+                id,
+                metadata,
+                last_activity,
+            });
+        }
+        chats.sort_by_key(|chat| Reverse(chat.last_activity));
+        Ok(chats)
+    }
+
+    /// Reopens a chat stored on this node, with a fresh subscription, and
+    /// resumes syncing with the peers it was synced with before.
+    pub async fn open_chat(&self, id: NamespaceId) -> Result<ChatClient, ChatError> {
+        let capability = self.capability(id).await?;
+        let chat = self.docs.open(id).await?.ok_or(ChatError::NotFound)?;
+        let sub = chat.subscribe().await?;
+        // the docs engine adds the stored sync peers itself
+        chat.start_sync(Vec::new())
+            .await
+            .map_err(ChatError::Network)?;
+        Ok(ChatClient {
+            chat,
+            sub: Some(Box::pin(sub)),
+            capability,
+        })
+    }
+
+    /// Returns a ticket for `client`'s chat. Write tickets need write
+    /// access and are never handed out for announcement channels.
+    pub async fn ticket(
+        &self,
+        client: &ChatClient,
+        mode: ShareMode,
+    ) -> Result<DocTicket, ChatError> {
+        if let ShareMode::Write = mode {
+            client.check_writable()?;
+            let metadata = client.metadata(Arc::new(self.clone())).await?;
+            if metadata.kind == ChatKind::Announcement {
+                return Err(ChatError::PermissionDenied);
+            }
+        }
+        Ok(client.chat.share(mode, self.addr_options).await?)
+    }
+
+    /// What the local replica of the chat `id` allows.
+    async fn capability(&self, id: NamespaceId) -> Result<CapabilityKind, ChatError> {
+        let ids: Vec<_> = self.docs.list().await?.try_collect().await?;
+        ids.into_iter()
+            .find(|(namespace, _)| *namespace == id)
+            .map(|(_, capability)| capability)
+            .ok_or(ChatError::NotFound)
+    }
+
+    /// Tells the other members that we leave, stops syncing the chat and
+    /// closes it. The history stays on this node.
+    pub async fn leave_chat(&self, mut client: ChatClient) -> Result<(), ChatError> {
+        self.leave(&mut client).await?;
+        client.close().await
+    }
+
+    /// Leaves the chat and removes it from this node, together with the
+    /// blobs no other chat refers to.
+    pub async fn delete_chat(&self, mut client: ChatClient) -> Result<(), ChatError> {
+        let id = client.id();
+        self.leave(&mut client).await?;
+        let hashes = client.referenced_blobs(self).await?;
+        client.close().await?;
+        self.docs.drop_doc(id).await?;
+        unpin_creator(&self.blobs, id).await?;
+
+        let in_use = self.blobs_in_use().await?;
+        for hash in hashes.difference(&in_use) {
+            self.blobs.delete_blob(*hash).await?;
+        }
+        Ok(())
+    }
+
+    /// Hashes of all blobs the chats stored on this node refer to.
+    pub(crate) async fn blobs_in_use(&self) -> Result<HashSet<Hash>, ChatError> {
+        let mut in_use = HashSet::new();
+        let ids: Vec<_> = self.docs.list().await?.try_collect().await?;
+        for (id, capability) in ids {
+            let Some(chat) = self.docs.open(id).await? else {
+                continue;
+            };
+            let client = ChatClient {
+                chat,
+                sub: None,
+                capability,
+            };
+            in_use.extend(client.referenced_blobs(self).await?);
+            client.close().await?;
+        }
+        Ok(in_use)
+    }
+
+    async fn leave(&self, client: &mut ChatClient) -> Result<(), ChatError> {
+        // readers leave silently
+        if !client.is_read_only() {
+            let envelope = Envelope::new(self.author, Message::new_leave(self.author));
+            let key = envelope.key();
+            // the live sync gossips the entry once it sees the insert, so
+            // wait for it before the sync is stopped
+            let mut events = client.chat.subscribe().await?;
+            client.send_envelope(envelope).await?;
+            let inserted = async {
+                while let Some(event) = events.next().await {
+                    if let Ok(LiveEvent::InsertLocal { entry }) = event
+                        && entry.key() == key.as_bytes()
+                    {
+                        break;
+                    }
+                }
+            };
+            let _ = tokio::time::timeout(LEAVE_TIMEOUT, inserted).await;
+        }
+        client.chat.leave().await?;
+        Ok(())
+    }
+
+    /// Joins the chat behind `ticket`.
+    ///
+    /// The ticket is checked before anything is written: it has to be a
+    /// well formed doc ticket, and at least one of the nodes it lists has to
+    /// be reachable.
+    ///
+    /// With a read-only ticket the chat can be followed but not posted to,
+    /// unless this node already holds write access to it.
+    pub async fn join_chat(&self, ticket: String) -> Result<ChatClient, ChatError> {
+        let a = self.clone();
+        let doct = parse_ticket(&ticket)?;
+        a.check_reachable(&doct).await?;
+        let (chat, sub) = a
+            .docs
+            .import_and_subscribe(doct)
+            .await
+            .map_err(ChatError::Storage)?;
         let sub = Pin::new(Box::new(sub));
-        let _ = chat
-            .set_bytes(
+        // importing keeps the stronger of the known and the new capability
+        let capability = a.capability(chat.id()).await?;
+        let client = ChatClient {
+            chat,
+            sub: Some(sub),
+            capability,
+        };
+        if !client.is_read_only() {
+            let announcement = Envelope::new(
                 a.author,
-                "chat-ticket",
-                bincode::serialize(&Message::set_ticket(a.author, ticket.clone())).unwrap(),
+                Message::set_ticket(a.author, normalize_ticket(&ticket)),
             )
-            .await;
-        Some(Ok(ChatClient { chat, sub }))
+            .encode()?;
+            client
+                .chat
+                .set_bytes(a.author, "chat-ticket", announcement)
+                .await?;
+        }
+        Ok(client)
+    }
+
+    /// Fails unless one of the nodes in `ticket` accepts a connection. A
+    /// ticket without any node addresses relies on discovery and passes.
+    async fn check_reachable(&self, ticket: &DocTicket) -> Result<(), ChatError> {
+        let endpoint = self.router.endpoint();
+        if ticket.nodes.is_empty()
+            || ticket
+                .nodes
+                .iter()
+                .any(|node| node.node_id == endpoint.node_id())
+        {
+            return Ok(());
+        }
+        // all nodes are tried at once, the first one answering wins and
+        // dropping the set aborts the others
+        let mut attempts = tokio::task::JoinSet::new();
+        for node in ticket.nodes.iter().cloned() {
+            let endpoint = endpoint.clone();
+            attempts.spawn(async move {
+                tokio::time::timeout(CONNECT_TIMEOUT, endpoint.connect(node, iroh_docs::ALPN)).await
+            });
+        }
+        let mut last_error = None;
+        while let Some(attempt) = attempts.join_next().await {
+            match attempt {
+                Ok(Ok(Ok(connection))) => {
+                    connection.close(0u32.into(), b"");
+                    return Ok(());
+                }
+                Ok(Ok(Err(e))) => last_error = Some(e),
+                Ok(Err(e)) => last_error = Some(e.into()),
+                Err(e) => last_error = Some(e.into()),
+            }
+        }
+        Err(ChatError::Network(last_error.unwrap_or_else(|| {
+            anyhow::anyhow!("none of the ticket's nodes is reachable")
+        })))
+    }
+}
+
+/// Parses `ticket`, telling tickets of other kinds apart from garbage.
+/// Tickets read from QR codes or wrapped across lines are accepted as
+/// well.
+fn parse_ticket(ticket: &str) -> Result<DocTicket, ChatError> {
+    let ticket = normalize_ticket(ticket);
+    let ticket = ticket.as_str();
+    for kind in ["blob", "node"] {
+        if ticket.starts_with(kind) {
+            return Err(ChatError::WrongTicketKind(kind.to_string()));
+        }
+    }
+    DocTicket::from_str(ticket).map_err(|e| ChatError::InvalidTicket(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_lite::StreamExt;
+
+    use super::*;
+    use crate::{client::ChatEvent, metadata::CREATOR_KEY};
+
+    fn doc_ticket() -> DocTicket {
+        let capability = iroh_docs::Capability::Read(NamespaceId::from(&[7; 32]));
+        let node = iroh::NodeAddr::new(SecretKey::from_bytes(&[1; 32]).public());
+        DocTicket::new(capability, vec![node])
+    }
+
+    #[test]
+    fn parse_ticket_rejects_garbage() {
+        for garbage in ["", "not a ticket", "docaaaa"] {
+            assert!(matches!(
+                parse_ticket(garbage),
+                Err(ChatError::InvalidTicket(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_ticket_rejects_other_kinds() {
+        let node = iroh::NodeAddr::new(SecretKey::from_bytes(&[1; 32]).public());
+        let blob = iroh_blobs::ticket::BlobTicket::new(
+            node,
+            Hash::new(b"file"),
+            iroh_blobs::BlobFormat::Raw,
+        )
+        .unwrap();
+        assert!(matches!(
+            parse_ticket(&blob.to_string()),
+            Err(ChatError::WrongTicketKind(kind)) if kind == "blob"
+        ));
+    }
+
+    #[test]
+    fn parse_ticket_accepts_qr_payloads() {
+        let ticket = doc_ticket().to_string();
+        // QR codes carry the ticket upper cased
+        let scanned = parse_ticket(&ticket.to_ascii_uppercase()).unwrap();
+        assert_eq!(scanned.to_string(), ticket);
+        let wrapped = format!("{}\n{}", &ticket[..10], &ticket[10..]);
+        assert_eq!(parse_ticket(&wrapped).unwrap().to_string(), ticket);
+    }
+
+    #[tokio::test]
+    async fn two_memory_nodes_exchange_a_message() {
+        let alice = Arc::new(Iroh::memory().await.unwrap());
+        let bob = Arc::new(Iroh::memory().await.unwrap());
+
+        let mut chat = alice
+            .create_chat(ChatKind::Group, Some("test".to_string()), None)
+            .await
+            .unwrap();
+        let ticket = alice.ticket(&chat, ShareMode::Write).await.unwrap();
+        let mut joined = bob.join_chat(ticket.to_string()).await.unwrap();
+        assert!(!joined.is_read_only());
+        let mut events = joined.events(bob.clone()).await.unwrap();
+
+        let sent = chat
+            .send_message(
+                alice.author,
+                Message::new_text(alice.author, "hello bob".to_string()),
+            )
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(60), async {
+            while let Some(event) = events.next().await {
+                if let ChatEvent::MessageReceived { envelope, .. } = event
+                    && envelope.id == sent
+                {
+                    return envelope;
+                }
+            }
+            panic!("the event stream ended");
+        })
+        .await
+        .expect("bob did not receive the message in time");
+        assert!(matches!(
+            received.message,
+            Message::TextMessage { content, .. } if content == "hello bob"
+        ));
+
+        drop(events);
+        joined.close().await.unwrap();
+        chat.close().await.unwrap();
+        bob.shutdown().await.unwrap();
+        alice.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn creator_claims_of_members_are_ignored() {
+        let node = Arc::new(Iroh::memory().await.unwrap());
+        let chat = node.create_chat(ChatKind::Group, None, None).await.unwrap();
+        let member = node.docs.authors().create().await.unwrap();
+        chat.chat
+            .set_bytes(member, CREATOR_KEY, member.to_string())
+            .await
+            .unwrap();
+
+        let metadata = chat.metadata(node.clone()).await.unwrap();
+        assert_eq!(metadata.creator, Some(node.author));
+        chat.close().await.unwrap();
+        node.shutdown().await.unwrap();
     }
 }
@@ -1,8 +1,13 @@
+pub mod attachment;
 pub mod cli;
 pub mod client;
+pub mod invite;
 pub mod iroh_client;
 pub mod message;
+pub mod metadata;
 
+// generated by rinf, not every signal is used yet
+#[allow(dead_code)]
 mod messages;
 mod sample_functions;
 
@@ -16,6 +21,23 @@ async fn main() {
     // or the equivalent provided by your async library.
     tokio::spawn(sample_functions::communicate());
 
+    // the app has no data dir of its own yet, so its node keeps everything
+    // in memory instead of writing to wherever it was started from
+    let node = match iroh_client::Iroh::memory().await {
+        Ok(node) => Some(node),
+        Err(e) => {
+            rinf::debug_print!("could not start the node: {e}");
+            None
+        }
+    };
+
     // Keep the main function running until Dart shutdown.
     rinf::dart_shutdown().await;
+
+    // Close connections before the app goes away.
+    if let Some(node) = node
+        && let Err(e) = node.shutdown().await
+    {
+        rinf::debug_print!("could not shut the node down: {e}");
+    }
 }
@@ -1,15 +1,139 @@
-use iroh_docs::AuthorId;
-use serde::{Deserialize, Serialize};
+use std::{cell::Cell, collections::BTreeMap, fmt, str::FromStr};
 
+use iroh::PublicKey;
+use iroh_blobs::Hash;
+use iroh_docs::{AuthorId, NamespaceId};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::metadata::MOVED_KEY;
+
+/// Version of the envelope layout written by this build.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Envelope metadata holding the id of the root of the thread a message
+/// was posted in.
+pub const THREAD_METADATA: &str = "thread";
+
+/// Envelope metadata holding the hash of the thumbnail of an image
+/// attachment.
+pub const THUMBNAIL_METADATA: &str = "thumbnail";
+
+/// Envelope metadata holding the size of the thumbnail in bytes.
+pub const THUMBNAIL_SIZE_METADATA: &str = "thumbnail_size";
+
+/// Envelope metadata holding the id of the node that sent an attachment.
+pub const UPLOADER_METADATA: &str = "uploader";
+
+/// Envelope metadata marking a message copied into a rotated chat. Holds
+/// the author that copied it, who signs the entry in place of the
+/// original author.
+pub const MIGRATED_METADATA: &str = "migrated";
+
+/// Magic bytes every encoded envelope starts with.
+const WIRE_MAGIC: &[u8; 3] = b"icm";
+/// Version of the wire header that follows the magic bytes. Version 2
+/// added the nonce to message ids.
+const WIRE_VERSION: u8 = 2;
+/// magic, wire version and the little endian message kind
+const HEADER_LEN: usize = WIRE_MAGIC.len() + 1 + 2;
+
+/// Message kinds are the variant index of the message, so new variants are
+/// only ever added right before `Unsupported` and existing ones are never
+/// reordered or changed. Anything that needs an extra field on an existing
+/// kind goes into the envelope metadata instead.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
-    TextMessage { author: AuthorId, content: String },
-    BlobMessage { author: AuthorId, content: Vec<u8> },
-    AuthorMessage { author: AuthorId, content: String },
-    ChatTicket { author: AuthorId, content: String },
+    TextMessage {
+        author: AuthorId,
+        content: String,
+    },
+    BlobMessage {
+        author: AuthorId,
+        content: Vec<u8>,
+    },
+    AuthorMessage {
+        author: AuthorId,
+        content: String,
+    },
+    ChatTicket {
+        author: AuthorId,
+        content: String,
+    },
+    /// A text message answering the message `reply_to`.
+    Reply {
+        author: AuthorId,
+        content: String,
+        reply_to: MessageId,
+    },
+    /// A new version of the message `target`, only valid when written by
+    /// the author of `target`.
+    Edit {
+        author: AuthorId,
+        target: MessageId,
+        content: String,
+    },
+    /// Tombstone replacing the message `target`, only valid when written by
+    /// the author of `target`.
+    Delete {
+        author: AuthorId,
+        target: MessageId,
+    },
+    /// Adds (`active`) or removes an emoji reaction to the message `target`.
+    /// Each author has one entry per target and emoji, the latest wins.
+    Reaction {
+        author: AuthorId,
+        target: MessageId,
+        emoji: String,
+        active: bool,
+    },
+    /// A file imported into the blob store, fetched by receivers on demand.
+    Attachment {
+        author: AuthorId,
+        hash: Hash,
+        size: u64,
+        name: String,
+        mime: String,
+    },
+    /// Announces that `author` left the chat and stopped syncing it.
+    Leave {
+        author: AuthorId,
+    },
+    /// The chat continues in the document `namespace`, only valid when
+    /// written by the creator of the chat.
+    Moved {
+        author: AuthorId,
+        namespace: NamespaceId,
+    },
+    /// A message of a kind this build does not know, written by a newer
+    /// peer. Never written to the doc, must stay the last variant.
+    #[serde(skip)]
+    Unsupported {
+        kind: u16,
+    },
 }
 
 impl Message {
+    /// Number of message kinds this build can decode.
+    const KNOWN_KINDS: u16 = 11;
+
+    /// The kind tag written into the wire header.
+    pub fn kind(&self) -> u16 {
+        match self {
+            Self::TextMessage { .. } => 0,
+            Self::BlobMessage { .. } => 1,
+            Self::AuthorMessage { .. } => 2,
+            Self::ChatTicket { .. } => 3,
+            Self::Reply { .. } => 4,
+            Self::Edit { .. } => 5,
+            Self::Delete { .. } => 6,
+            Self::Reaction { .. } => 7,
+            Self::Attachment { .. } => 8,
+            Self::Leave { .. } => 9,
+            Self::Moved { .. } => 10,
+            Self::Unsupported { kind } => *kind,
+        }
+    }
+
     pub fn new_text(author: AuthorId, content: String) -> Self {
         Self::TextMessage { author, content }
     }
@@ -25,4 +149,630 @@ impl Message {
     pub fn set_ticket(author: AuthorId, content: String) -> Self {
         Self::ChatTicket { author, content }
     }
+    pub fn new_reply(author: AuthorId, content: String, reply_to: MessageId) -> Self {
+        Self::Reply {
+            author,
+            content,
+            reply_to,
+        }
+    }
+    pub fn new_edit(author: AuthorId, target: MessageId, content: String) -> Self {
+        Self::Edit {
+            author,
+            target,
+            content,
+        }
+    }
+    pub fn new_delete(author: AuthorId, target: MessageId) -> Self {
+        Self::Delete { author, target }
+    }
+    pub fn new_attachment(
+        author: AuthorId,
+        hash: Hash,
+        size: u64,
+        name: String,
+        mime: String,
+    ) -> Self {
+        Self::Attachment {
+            author,
+            hash,
+            size,
+            name,
+            mime,
+        }
+    }
+    pub fn new_leave(author: AuthorId) -> Self {
+        Self::Leave { author }
+    }
+    pub fn new_moved(author: AuthorId, namespace: NamespaceId) -> Self {
+        Self::Moved { author, namespace }
+    }
+    pub fn new_reaction(author: AuthorId, target: MessageId, emoji: String, active: bool) -> Self {
+        Self::Reaction {
+            author,
+            target,
+            emoji,
+            active,
+        }
+    }
+}
+
+/// Identifies a message across peers.
+///
+/// The same author may write from several devices and a clock may step
+/// back, so the sender timestamp and the author alone can repeat; a random
+/// nonce tells such messages apart. The id is also what the message entry
+/// is keyed by, see [`MessageId::key`]. Messages written before ids had a
+/// nonce have a nonce of 0 and keep their old key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct MessageId {
+    /// sender timestamp in microseconds since the unix epoch
+    pub timestamp: u64,
+    pub nonce: u32,
+    pub author: AuthorId,
+}
+
+thread_local! {
+    /// Set while decoding entries written before ids had a nonce.
+    static IDS_WITHOUT_NONCE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `decode` reading every [`MessageId`] in the old layout, without
+/// a nonce.
+fn without_nonce<T>(decode: impl FnOnce() -> T) -> T {
+    IDS_WITHOUT_NONCE.set(true);
+    let decoded = decode();
+    IDS_WITHOUT_NONCE.set(false);
+    decoded
+}
+
+impl<'de> Deserialize<'de> for MessageId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if IDS_WITHOUT_NONCE.get() {
+            let (timestamp, author) = <(u64, AuthorId)>::deserialize(deserializer)?;
+            return Ok(Self {
+                timestamp,
+                nonce: 0,
+                author,
+            });
+        }
+        let (timestamp, nonce, author) = <(u64, u32, AuthorId)>::deserialize(deserializer)?;
+        Ok(Self {
+            timestamp,
+            nonce,
+            author,
+        })
+    }
+}
+
+impl MessageId {
+    /// Key prefix shared by all message entries in a chat doc.
+    pub const KEY_PREFIX: &'static str = "msg/";
+
+    pub fn new(author: AuthorId) -> Self {
+        let timestamp = std::time::UNIX_EPOCH
+            .elapsed()
+            .unwrap_or_default()
+            .as_micros() as u64;
+        Self {
+            timestamp,
+            nonce: rand::random(),
+            author,
+        }
+    }
+
+    /// The doc key the message is stored under.
+    pub fn key(&self) -> String {
+        format!("{}{}", Self::KEY_PREFIX, self)
+    }
+
+    /// Parses a key produced by [`MessageId::key`].
+    pub fn from_key(key: &str) -> Option<Self> {
+        key.strip_prefix(Self::KEY_PREFIX)?.parse().ok()
+    }
+
+    /// Key prefix of all edits of this message.
+    pub fn edits_prefix(&self) -> String {
+        format!("edit/{}/", self)
+    }
+
+    /// Key prefix of all reactions to this message.
+    pub fn reactions_prefix(&self) -> String {
+        format!("react/{}/", self)
+    }
+
+    /// Key prefix shared by the messages of all threads.
+    pub const THREAD_KEY_PREFIX: &'static str = "thread/";
+
+    /// Key prefix of the messages in the thread started by this message.
+    pub fn thread_prefix(&self) -> String {
+        format!("{}{}/", Self::THREAD_KEY_PREFIX, self)
+    }
+
+    /// Parses a thread message key into the thread root and the message id.
+    pub fn from_thread_key(key: &str) -> Option<(Self, Self)> {
+        let (root, id) = key.strip_prefix(Self::THREAD_KEY_PREFIX)?.split_once('/')?;
+        Some((root.parse().ok()?, id.parse().ok()?))
+    }
+}
+
+impl fmt::Display for MessageId {
+    // the timestamp is zero padded and comes first so that key order is
+    // send order
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.nonce {
+            0 => write!(f, "{:020}-{}", self.timestamp, self.author),
+            nonce => write!(f, "{:020}-{:08x}-{}", self.timestamp, nonce, self.author),
+        }
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (timestamp, rest) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("missing separator in message id"))?;
+        let (nonce, author) = match rest.split_once('-') {
+            Some((nonce, author)) => (u32::from_str_radix(nonce, 16)?, author),
+            None => (0, rest),
+        };
+        Ok(Self {
+            timestamp: timestamp.parse()?,
+            nonce,
+            author: author.parse()?,
+        })
+    }
+}
+
+/// What actually gets written to the chat doc: a [`Message`] together with
+/// its id, the protocol version it was written with and free form metadata.
+///
+/// On the wire the envelope is prefixed by a small header carrying the
+/// message kind, see [`Envelope::encode`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u16,
+    pub id: MessageId,
+    pub metadata: BTreeMap<String, String>,
+    /// must stay the last field, see [`EnvelopeHead`]
+    pub message: Message,
+}
+
+/// The fields of an [`Envelope`] in front of the message. Lets us read the
+/// id of a message whose kind we don't understand.
+#[derive(Deserialize)]
+struct EnvelopeHead {
+    version: u16,
+    id: MessageId,
+    metadata: BTreeMap<String, String>,
+}
+
+impl Envelope {
+    pub fn new(author: AuthorId, message: Message) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id: MessageId::new(author),
+            metadata: BTreeMap::new(),
+            message,
+        }
+    }
+
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Posts the message in the thread started by `root`.
+    pub fn in_thread(self, root: MessageId) -> Self {
+        self.with_metadata(THREAD_METADATA, root.to_string())
+    }
+
+    /// The root of the thread the message was posted in, if any.
+    pub fn thread(&self) -> Option<MessageId> {
+        self.metadata.get(THREAD_METADATA)?.parse().ok()
+    }
+
+    /// The hash of the thumbnail of an image attachment, if it has one.
+    pub fn thumbnail(&self) -> Option<Hash> {
+        self.metadata.get(THUMBNAIL_METADATA)?.parse().ok()
+    }
+
+    /// The size of the thumbnail in bytes, as claimed by the sender.
+    pub fn thumbnail_size(&self) -> Option<u64> {
+        self.metadata.get(THUMBNAIL_SIZE_METADATA)?.parse().ok()
+    }
+
+    /// The node that sent the attachment, which has it for sure.
+    pub fn uploader(&self) -> Option<PublicKey> {
+        self.metadata.get(UPLOADER_METADATA)?.parse().ok()
+    }
+
+    /// The author that copied the message into a rotated chat, if it was
+    /// copied.
+    pub fn migrated_by(&self) -> Option<AuthorId> {
+        self.metadata.get(MIGRATED_METADATA)?.parse().ok()
+    }
+
+    pub fn author(&self) -> AuthorId {
+        self.id.author
+    }
+
+    /// The doc key the envelope is stored under. Edits are kept next to
+    /// each other below their target so every version stays queryable,
+    /// tombstones overwrite the message they delete and reactions
+    /// overwrite the author's previous reaction with the same emoji.
+    /// Messages posted in a thread live below the thread root, and the
+    /// move marker has a reserved key of its own.
+    pub fn key(&self) -> String {
+        let id = match &self.message {
+            Message::Edit { target, .. } => {
+                return match self.id.nonce {
+                    0 => format!("{}{:020}", target.edits_prefix(), self.id.timestamp),
+                    nonce => format!(
+                        "{}{:020}-{:08x}",
+                        target.edits_prefix(),
+                        self.id.timestamp,
+                        nonce
+                    ),
+                };
+            }
+            Message::Reaction { target, emoji, .. } => {
+                return format!("{}{}", target.reactions_prefix(), emoji);
+            }
+            Message::Delete { target, .. } => target,
+            Message::Moved { .. } => return MOVED_KEY.to_string(),
+            _ => &self.id,
+        };
+        match self.thread() {
+            Some(root) => format!("{}{}", root.thread_prefix(), id),
+            None => id.key(),
+        }
+    }
+
+    /// Whether the signing author is allowed to write this message.
+    fn is_authorized(&self) -> bool {
+        match &self.message {
+            Message::Edit { target, .. } | Message::Delete { target, .. } => {
+                target.author == self.author()
+            }
+            _ => true,
+        }
+    }
+
+    /// sender timestamp in microseconds since the unix epoch
+    pub fn timestamp(&self) -> u64 {
+        self.id.timestamp
+    }
+
+    /// Encodes the envelope as the wire header followed by the bincode
+    /// encoded envelope.
+    pub fn encode(&self) -> Result<Vec<u8>, bincode::Error> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(WIRE_MAGIC);
+        bytes.push(WIRE_VERSION);
+        bytes.extend_from_slice(&self.message.kind().to_le_bytes());
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Decodes the content of the entry `key` written by `author`.
+    ///
+    /// Kinds this build does not know decode to [`Message::Unsupported`]
+    /// with the id and metadata of the envelope intact. Entries written
+    /// before the wire header existed are decoded as plain envelopes, and
+    /// entries written before envelopes existed hold a bare [`Message`]
+    /// under a plain timestamp key; those are wrapped into an envelope with
+    /// the id they would have had. Envelopes claiming a different author
+    /// than the one that signed the entry are rejected, as are edits and
+    /// tombstones of somebody else's message. The only exception are
+    /// copies made when a chat was rotated: those are accepted if they
+    /// were both signed and marked as migrated by `migrator`, which should
+    /// be the chat's creator.
+    pub fn decode(
+        key: &str,
+        author: AuthorId,
+        migrator: Option<AuthorId>,
+        bytes: &[u8],
+    ) -> Option<Self> {
+        let envelope = match bytes.strip_prefix(WIRE_MAGIC) {
+            Some(rest) => Self::decode_framed(rest)?,
+            None => Self::decode_legacy(key, author, bytes)?,
+        };
+        let migrated = migrator == Some(author) && envelope.migrated_by() == Some(author);
+        let signed_by_author = envelope.author() == author || migrated;
+        (signed_by_author && envelope.is_authorized()).then_some(envelope)
+    }
+
+    fn decode_framed(bytes: &[u8]) -> Option<Self> {
+        let (&wire_version, rest) = bytes.split_first()?;
+        match wire_version {
+            1 => without_nonce(|| Self::decode_body(rest)),
+            WIRE_VERSION => Self::decode_body(rest),
+            _ => None,
+        }
+    }
+
+    /// Decodes what follows the wire version: the kind and the envelope.
+    fn decode_body(rest: &[u8]) -> Option<Self> {
+        if rest.len() < 2 {
+            return None;
+        }
+        let (kind, body) = rest.split_at(2);
+        let kind = u16::from_le_bytes([kind[0], kind[1]]);
+        if kind < Message::KNOWN_KINDS {
+            return bincode::deserialize(body).ok();
+        }
+        let head: EnvelopeHead = bincode::deserialize(body).ok()?;
+        Some(Self {
+            version: head.version,
+            id: head.id,
+            metadata: head.metadata,
+            message: Message::Unsupported { kind },
+        })
+    }
+
+    fn decode_legacy(key: &str, author: AuthorId, bytes: &[u8]) -> Option<Self> {
+        if let Ok(envelope) = without_nonce(|| bincode::deserialize::<Envelope>(bytes)) {
+            return Some(envelope);
+        }
+        let message = without_nonce(|| bincode::deserialize::<Message>(bytes)).ok()?;
+        let timestamp = key.parse().unwrap_or_default();
+        Some(Self {
+            version: 0,
+            id: MessageId {
+                timestamp,
+                nonce: 0,
+                author,
+            },
+            metadata: BTreeMap::new(),
+            message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A valid author id, ids of migrators are parsed back as public keys.
+    fn author(byte: u8) -> AuthorId {
+        iroh_docs::Author::from_bytes(&[byte; 32]).id()
+    }
+
+    fn text(author: AuthorId, content: &str) -> Envelope {
+        Envelope::new(author, Message::new_text(author, content.to_string()))
+    }
+
+    #[test]
+    fn framed_round_trip() {
+        let alice = author(1);
+        let envelope = text(alice, "hello").in_thread(MessageId::new(author(2)));
+        let bytes = envelope.encode().unwrap();
+        assert!(bytes.starts_with(WIRE_MAGIC));
+        assert_eq!(bytes[WIRE_MAGIC.len()], WIRE_VERSION);
+
+        let decoded = Envelope::decode(&envelope.key(), alice, None, &bytes).unwrap();
+        assert_eq!(decoded.id, envelope.id);
+        assert_eq!(decoded.version, PROTOCOL_VERSION);
+        assert_eq!(decoded.thread(), envelope.thread());
+        assert!(matches!(
+            decoded.message,
+            Message::TextMessage { author, content } if author == alice && content == "hello"
+        ));
+    }
+
+    #[test]
+    fn round_trip_keeps_kind() {
+        let alice = author(1);
+        let target = MessageId::new(alice);
+        let messages = [
+            Message::new_reply(alice, "re".to_string(), target),
+            Message::new_edit(alice, target, "fixed".to_string()),
+            Message::new_delete(alice, target),
+            Message::new_reaction(alice, target, "👍".to_string(), true),
+            Message::new_leave(alice),
+        ];
+        for message in messages {
+            let kind = message.kind();
+            let envelope = Envelope::new(alice, message);
+            let bytes = envelope.encode().unwrap();
+            let decoded = Envelope::decode(&envelope.key(), alice, None, &bytes).unwrap();
+            assert_eq!(decoded.message.kind(), kind);
+            assert_eq!(decoded.id, envelope.id);
+        }
+    }
+
+    #[test]
+    fn thread_edits_and_reactions_stay_keyed_by_target() {
+        let alice = author(1);
+        let (root, target) = (MessageId::new(alice), MessageId::new(alice));
+        for message in [
+            Message::new_edit(alice, target, "fixed".to_string()),
+            Message::new_reaction(alice, target, "👍".to_string(), true),
+        ] {
+            let envelope = Envelope::new(alice, message);
+            let key = envelope.key();
+            let envelope = envelope.in_thread(root);
+            assert_eq!(envelope.key(), key);
+            assert_eq!(envelope.thread(), Some(root));
+        }
+    }
+
+    #[test]
+    fn decodes_bare_legacy_message() {
+        let alice = author(1);
+        let bytes = bincode::serialize(&Message::new_text(alice, "old".to_string())).unwrap();
+        let decoded = Envelope::decode("00000000000000000042", alice, None, &bytes).unwrap();
+        assert_eq!(decoded.version, 0);
+        assert_eq!(
+            decoded.id,
+            MessageId {
+                timestamp: 42,
+                nonce: 0,
+                author: alice
+            }
+        );
+        assert!(decoded.metadata.is_empty());
+        assert!(
+            matches!(decoded.message, Message::TextMessage { content, .. } if content == "old")
+        );
+    }
+
+    #[test]
+    fn decodes_unframed_envelope() {
+        let alice = author(1);
+        let message = Message::new_text(alice, "before the header".to_string());
+        // ids had no nonce back then
+        let bytes = bincode::serialize(&(
+            1u16,
+            (42u64, alice),
+            BTreeMap::<String, String>::new(),
+            message,
+        ))
+        .unwrap();
+        let id = MessageId {
+            timestamp: 42,
+            nonce: 0,
+            author: alice,
+        };
+        let decoded = Envelope::decode(&id.key(), alice, None, &bytes).unwrap();
+        assert_eq!(decoded.id, id);
+        assert!(matches!(
+            decoded.message,
+            Message::TextMessage { content, .. } if content == "before the header"
+        ));
+    }
+
+    #[test]
+    fn decodes_ids_without_nonce_of_wire_version_1() {
+        let (alice, bob) = (author(1), author(2));
+        let message = Message::new_reply(alice, "re".to_string(), MessageId::new(bob));
+        let mut bytes = WIRE_MAGIC.to_vec();
+        bytes.push(1);
+        bytes.extend_from_slice(&message.kind().to_le_bytes());
+        // the old layout of the reply, with both ids lacking the nonce
+        let body = (message.kind() as u32, alice, "re", (7u64, bob));
+        bincode::serialize_into(
+            &mut bytes,
+            &(
+                1u16,
+                (42u64, alice),
+                BTreeMap::<String, String>::new(),
+                body,
+            ),
+        )
+        .unwrap();
+
+        let decoded = Envelope::decode("msg/x", alice, None, &bytes).unwrap();
+        assert_eq!(decoded.id.to_string(), format!("{:020}-{}", 42, alice));
+        let Message::Reply { reply_to, .. } = decoded.message else {
+            panic!("not a reply");
+        };
+        assert_eq!(
+            reply_to,
+            MessageId {
+                timestamp: 7,
+                nonce: 0,
+                author: bob
+            }
+        );
+    }
+
+    #[test]
+    fn message_id_string_round_trip() {
+        let alice = author(1);
+        let id = MessageId {
+            timestamp: 42,
+            nonce: 0xbeef,
+            author: alice,
+        };
+        assert_eq!(id.to_string(), format!("{:020}-0000beef-{}", 42, alice));
+        let old = MessageId { nonce: 0, ..id };
+        for id in [id, old] {
+            assert_eq!(MessageId::from_key(&id.key()), Some(id));
+        }
+        // same author, same microsecond, e.g. from two devices
+        let (a, b) = (MessageId::new(alice), MessageId::new(alice));
+        assert_ne!(
+            MessageId { timestamp: 42, ..a },
+            MessageId { timestamp: 42, ..b }
+        );
+    }
+
+    #[test]
+    fn unknown_kind_is_unsupported() {
+        let alice = author(1);
+        let id = MessageId::new(alice);
+        let mut metadata = BTreeMap::new();
+        metadata.insert("future".to_string(), "yes".to_string());
+        let mut bytes = WIRE_MAGIC.to_vec();
+        bytes.push(WIRE_VERSION);
+        bytes.extend_from_slice(&999u16.to_le_bytes());
+        bincode::serialize_into(&mut bytes, &(7u16, id, &metadata, "a body from the future"))
+            .unwrap();
+
+        let decoded = Envelope::decode(&id.key(), alice, None, &bytes).unwrap();
+        assert_eq!(decoded.id, id);
+        assert_eq!(decoded.version, 7);
+        assert_eq!(decoded.metadata, metadata);
+        assert!(matches!(
+            decoded.message,
+            Message::Unsupported { kind: 999 }
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_wire_version() {
+        let alice = author(1);
+        let envelope = text(alice, "hello");
+        let mut bytes = envelope.encode().unwrap();
+        bytes[WIRE_MAGIC.len()] = WIRE_VERSION + 1;
+        assert!(Envelope::decode(&envelope.key(), alice, None, &bytes).is_none());
+    }
+
+    #[test]
+    fn rejects_envelopes_of_other_authors() {
+        let (alice, mallory) = (author(1), author(2));
+        let envelope = text(alice, "not from mallory");
+        let bytes = envelope.encode().unwrap();
+        assert!(Envelope::decode(&envelope.key(), mallory, None, &bytes).is_none());
+    }
+
+    #[test]
+    fn rejects_foreign_edits_and_tombstones() {
+        let (alice, mallory) = (author(1), author(2));
+        let target = MessageId::new(alice);
+        for message in [
+            Message::new_edit(mallory, target, "mine now".to_string()),
+            Message::new_delete(mallory, target),
+        ] {
+            let envelope = Envelope::new(mallory, message);
+            let bytes = envelope.encode().unwrap();
+            assert!(Envelope::decode(&envelope.key(), mallory, None, &bytes).is_none());
+        }
+    }
+
+    #[test]
+    fn trusts_migrated_copies_of_the_creator_only() {
+        let (alice, creator, mallory) = (author(1), author(2), author(3));
+        let key = text(alice, "copied").key();
+        let copy = |signer: AuthorId| {
+            text(alice, "copied")
+                .with_metadata(MIGRATED_METADATA, signer.to_string())
+                .encode()
+                .unwrap()
+        };
+
+        let bytes = copy(creator);
+        let decoded = Envelope::decode(&key, creator, Some(creator), &bytes).unwrap();
+        assert_eq!(decoded.author(), alice);
+        assert_eq!(decoded.migrated_by(), Some(creator));
+        // without a known creator nobody may sign for someone else
+        assert!(Envelope::decode(&key, creator, None, &bytes).is_none());
+        // naming yourself as the migrator is not enough
+        let bytes = copy(mallory);
+        assert!(Envelope::decode(&key, mallory, Some(creator), &bytes).is_none());
+    }
 }
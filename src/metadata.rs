@@ -0,0 +1,311 @@
+use std::{collections::HashSet, sync::Arc};
+
+use futures_lite::StreamExt;
+use iroh_blobs::{Hash, HashAndFormat};
+use iroh_docs::{AuthorId, NamespaceId, store::Query};
+
+use crate::{
+    client::{ChatC, ChatClient, ChatError, read_envelope},
+    iroh_client::{BlobsClient, Iroh},
+    message::Message,
+};
+
+/// Prefix of the doc keys reserved for chat metadata. Values are stored as
+/// plain text.
+pub const META_PREFIX: &str = "meta/";
+pub const TITLE_KEY: &str = "meta/title";
+pub const TOPIC_KEY: &str = "meta/topic";
+/// hash of the avatar image blob
+pub const AVATAR_KEY: &str = "meta/avatar";
+/// creation time in microseconds since the unix epoch
+pub const CREATED_KEY: &str = "meta/created";
+/// the author that created the chat
+pub const CREATOR_KEY: &str = "meta/creator";
+/// the [`ChatKind`], only honored when written by the creator
+pub const KIND_KEY: &str = "meta/kind";
+/// the [`Message::Moved`] marker of a rotated chat, an envelope rather than
+/// plain text and only honored when written by the creator
+pub const MOVED_KEY: &str = "meta/moved";
+
+/// Prefix of the blob tags pinning the creator of each chat on this node.
+const CREATOR_TAG_PREFIX: &str = "chat-creator/";
+
+/// Who may post to a chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChatKind {
+    /// Everyone with a write ticket posts.
+    #[default]
+    Group,
+    /// Only the creator posts, everyone else gets read-only tickets.
+    Announcement,
+}
+
+impl ChatKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Group => "group",
+            Self::Announcement => "announcement",
+        }
+    }
+}
+
+/// Chat-level information stored next to the messages.
+#[derive(Debug, Clone, Default)]
+pub struct ChatMetadata {
+    pub title: Option<String>,
+    pub topic: Option<String>,
+    /// read it with [`ChatClient::avatar`]
+    pub avatar: Option<Hash>,
+    /// creation time in microseconds since the unix epoch
+    pub created: Option<u64>,
+    pub creator: Option<AuthorId>,
+    pub kind: ChatKind,
+}
+
+impl ChatClient {
+    /// Reads all metadata of the chat. Title, topic and avatar are the
+    /// latest values written by any member.
+    pub async fn metadata(&self, iroh: Arc<Iroh>) -> Result<ChatMetadata, ChatError> {
+        let creator = self.creator(&iroh).await?;
+        let (created, kind) = match creator {
+            Some(creator) => {
+                let created = self
+                    .value(&iroh, Query::author(creator).key_exact(CREATED_KEY).build())
+                    .await?
+                    .and_then(|created| created.parse().ok());
+                let kind = self
+                    .value(&iroh, Query::author(creator).key_exact(KIND_KEY).build())
+                    .await?;
+                let kind = match kind.as_deref() {
+                    Some("announcement") => ChatKind::Announcement,
+                    _ => ChatKind::Group,
+                };
+                (created, kind)
+            }
+            None => (None, ChatKind::Group),
+        };
+        Ok(ChatMetadata {
+            title: self.latest(&iroh, TITLE_KEY).await?,
+            topic: self.latest(&iroh, TOPIC_KEY).await?,
+            avatar: self
+                .latest(&iroh, AVATAR_KEY)
+                .await?
+                .and_then(|hash| hash.parse().ok()),
+            created,
+            creator,
+            kind,
+        })
+    }
+
+    pub async fn set_title(&mut self, author: AuthorId, title: String) -> Result<(), ChatError> {
+        self.check_writable()?;
+        self.chat.set_bytes(author, TITLE_KEY, title).await?;
+        Ok(())
+    }
+
+    pub async fn set_topic(&mut self, author: AuthorId, topic: String) -> Result<(), ChatError> {
+        self.check_writable()?;
+        self.chat.set_bytes(author, TOPIC_KEY, topic).await?;
+        Ok(())
+    }
+
+    /// Adds `image` to the blob store and makes it the chat's avatar.
+    pub async fn set_avatar(
+        &mut self,
+        iroh: Arc<Iroh>,
+        author: AuthorId,
+        image: Vec<u8>,
+    ) -> Result<Hash, ChatError> {
+        self.check_writable()?;
+        let hash = iroh.blobs.add_bytes(image).await?.hash;
+        self.chat
+            .set_bytes(author, AVATAR_KEY, hash.to_string())
+            .await?;
+        Ok(hash)
+    }
+
+    /// Returns the avatar image, if the chat has one and it was fetched
+    /// already.
+    pub async fn avatar(&self, iroh: Arc<Iroh>) -> Result<Option<Vec<u8>>, ChatError> {
+        let Some(hash) = self
+            .latest(&iroh, AVATAR_KEY)
+            .await?
+            .and_then(|hash| hash.parse::<Hash>().ok())
+        else {
+            return Ok(None);
+        };
+        match iroh.blobs.read_to_bytes(hash).await {
+            Ok(image) => Ok(Some(image.to_vec())),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Records `author` as the creator of a chat of `kind`, done once by
+    /// [`Iroh::create_chat`].
+    pub(crate) async fn set_creator(
+        &mut self,
+        author: AuthorId,
+        kind: ChatKind,
+    ) -> Result<(), ChatError> {
+        let created = std::time::UNIX_EPOCH
+            .elapsed()
+            .unwrap_or_default()
+            .as_micros() as u64;
+        self.chat
+            .set_bytes(author, CREATED_KEY, created.to_string())
+            .await?;
+        self.chat
+            .set_bytes(author, CREATOR_KEY, author.to_string())
+            .await?;
+        self.chat.set_bytes(author, KIND_KEY, kind.as_str()).await?;
+        Ok(())
+    }
+
+    /// Writes `metadata` of a rotated chat into this one, keeping the
+    /// original creation time. `author` has to be the original creator.
+    pub(crate) async fn copy_metadata(
+        &mut self,
+        author: AuthorId,
+        metadata: &ChatMetadata,
+    ) -> Result<(), ChatError> {
+        self.set_creator(author, metadata.kind).await?;
+        if let Some(created) = metadata.created {
+            self.chat
+                .set_bytes(author, CREATED_KEY, created.to_string())
+                .await?;
+        }
+        if let Some(title) = &metadata.title {
+            self.set_title(author, title.clone()).await?;
+        }
+        if let Some(topic) = &metadata.topic {
+            self.set_topic(author, topic.clone()).await?;
+        }
+        if let Some(avatar) = metadata.avatar {
+            self.chat
+                .set_bytes(author, AVATAR_KEY, avatar.to_string())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The namespace the chat moved to, if its creator rotated it.
+    pub async fn moved_to(&self, iroh: Arc<Iroh>) -> Result<Option<NamespaceId>, ChatError> {
+        let Some(creator) = self.creator(&iroh).await? else {
+            return Ok(None);
+        };
+        let query = Query::author(creator).key_exact(MOVED_KEY).build();
+        let Some(entry) = self.chat.get_one(query).await? else {
+            return Ok(None);
+        };
+        let envelope = read_envelope(
+            &iroh.blobs,
+            MOVED_KEY,
+            entry.author(),
+            None,
+            entry.content_hash(),
+        )
+        .await;
+        match envelope.map(|envelope| envelope.message) {
+            Some(Message::Moved { namespace, .. }) => Ok(Some(namespace)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The author that created the chat, see [`chat_creator`].
+    pub async fn creator(&self, iroh: &Iroh) -> Result<Option<AuthorId>, ChatError> {
+        chat_creator(&self.chat, &iroh.blobs).await
+    }
+
+    /// The value most recently written under `key` by any author.
+    async fn latest(&self, iroh: &Iroh, key: &str) -> Result<Option<String>, ChatError> {
+        self.value(iroh, Query::single_latest_per_key().key_exact(key).build())
+            .await
+    }
+
+    async fn value(&self, iroh: &Iroh, query: Query) -> Result<Option<String>, ChatError> {
+        let Some(entry) = self.chat.get_one(query).await? else {
+            return Ok(None);
+        };
+        // the entry may have synced before its content, treat the value as
+        // unknown until it is there
+        let Ok(value) = iroh.blobs.read_to_bytes(entry.content_hash()).await else {
+            return Ok(None);
+        };
+        // any member can write garbage here, which must not break listing
+        // the chat
+        let Ok(value) = String::from_utf8(value.to_vec()) else {
+            return Ok(None);
+        };
+        Ok(Some(value))
+    }
+}
+
+/// The author that created the chat behind `chat`.
+///
+/// Anyone with write access can sign a creator entry with any timestamp,
+/// so the creator is pinned on this node instead of being read from the
+/// doc every time: chats created or rotated here pin their creator right
+/// away, chats followed through a move marker inherit the creator of the
+/// old chat. Joined chats trust the first creator they see, but only while
+/// a single author claims to be it; with conflicting claims the creator
+/// stays unknown.
+pub(crate) async fn chat_creator(
+    chat: &ChatC,
+    blobs: &BlobsClient,
+) -> Result<Option<AuthorId>, ChatError> {
+    if let Some(pinned) = blobs.tags().get(creator_tag(chat.id())).await? {
+        let creator = blobs.read_to_bytes(pinned.hash).await?;
+        let creator = std::str::from_utf8(&creator)
+            .ok()
+            .and_then(|creator| creator.parse().ok());
+        return Ok(creator);
+    }
+    let query = Query::key_exact(CREATOR_KEY).build();
+    let mut entries = Box::pin(chat.get_many(query).await?);
+    let mut claims = HashSet::new();
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        // content that has not synced yet can't be checked, skip it
+        let Ok(claimed) = blobs.read_to_bytes(entry.content_hash()).await else {
+            continue;
+        };
+        if claimed[..] == *entry.author().to_string().as_bytes() {
+            claims.insert(entry.author());
+        }
+    }
+    let mut claims = claims.into_iter();
+    match (claims.next(), claims.next()) {
+        (Some(creator), None) => {
+            pin_creator(blobs, chat.id(), creator).await?;
+            Ok(Some(creator))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Records `creator` as the creator of the chat `id` on this node, see
+/// [`chat_creator`].
+pub(crate) async fn pin_creator(
+    blobs: &BlobsClient,
+    id: NamespaceId,
+    creator: AuthorId,
+) -> Result<(), ChatError> {
+    // the same content as the creator entry, the tag also keeps it around
+    let hash = blobs.add_bytes(creator.to_string()).await?.hash;
+    blobs
+        .tags()
+        .set(creator_tag(id), HashAndFormat::raw(hash))
+        .await?;
+    Ok(())
+}
+
+/// Drops the creator pinned for the chat `id`.
+pub(crate) async fn unpin_creator(blobs: &BlobsClient, id: NamespaceId) -> Result<(), ChatError> {
+    blobs.tags().delete(creator_tag(id)).await?;
+    Ok(())
+}
+
+/// Name of the blob tag pinning the creator of the chat `id`.
+fn creator_tag(id: NamespaceId) -> String {
+    format!("{}{}", CREATOR_TAG_PREFIX, id)
+}